@@ -4,37 +4,66 @@ import { Mode } from  "./mode";
 type Id = string;
 
 type MsgToReceiver
-    = { type: "HandshakeAck", id: Id }
+    = { type: "HandshakeAck", id: Id, reconnect_token: string, token: string|null }
+    | { type: "HandshakeRejected", reason: RejectReason }
     | { type: "FilesAdded", files: File[] }
     | { type: "FilesRemoved", files: File[] }
-    | { type: "FileList", files: File[] };
+    | { type: "FileList", files: File[] }
+    | { type: "FileUnavailable", file_id: Id, reason: UnavailableReason }
+    | { type: "ShareUnavailable", reason: UnavailableReason }
+    | { type: "SenderOffline" }
+    | { type: "SenderOnline", token: string|null }
+    | { type: "DownloadReady", file_id: Id }
+    | { type: "RateLimited" };
 
 type MsgFromReceiver
-    = { type: "Handshake", id: Id|null }
+    = { type: "Handshake", id: Id|null, reconnect_token?: string|null, password?: string|null }
     | { type: "PleaseUpload", file_id: Id, stream_id: Id }
-    | { type: "PleaseFileList" };
+    | { type: "PleaseFileList" }
+    | { type: "QueueDownload", file_id: Id };
 
 type MsgToSender
-    = { type: "HandshakeAck", id: Id }
-    | { type: "PleaseUpload", file_id: Id, stream_id: Id }
-    | { type: "PleaseFileList", receiver_id: Id };
+    = { type: "HandshakeAck", id: Id, reconnect_token: string }
+    | { type: "HandshakeRejected", reason: RejectReason }
+    | { type: "PleaseUpload", file_id: Id, stream_id: Id, offset: number, length: number|null }
+    | { type: "PleaseFileList", receiver_id: Id }
+    | { type: "UploadFinished", stream_id: Id, error: string|null }
+    | { type: "IncomingFile", stream_id: Id, info: FileInfoForStream };
 
 type MsgFromSender
-    = { type: "Handshake", id: Id|null }
+    = { type: "Handshake", id: Id|null, reconnect_token?: string|null, password?: string|null }
     | { type: "FilesAdded", receiver_id: Id|null, files: File[] }
     | { type: "FilesRemoved", receiver_id: Id|null, files: File[] }
     | { type: "FileList", receiver_id: Id|null, files: File[] }
-    | { type: "PleaseUploadAck", stream_id: Id, info: FileInfoForStream };
+    | { type: "PleaseUploadAck", stream_id: Id, info: FileInfoForStream }
+    | { type: "PleaseUploadNack", stream_id: Id, reason: NackReason }
+    | { type: "DropBox", enabled: boolean }
+    | { type: "AcceptIncoming", stream_id: Id }
+    | { type: "RejectIncoming", stream_id: Id, reason: string|null }
+    | { type: "SetLimits", file_id: Id|null, expires_in: number|null, max_downloads: number|null };
+
+type NackReason = "NotFound" | "Unavailable";
+
+type UnavailableReason = "Expired" | "DownloadLimitReached";
+
+type RejectReason = "PasswordRequired" | "PasswordIncorrect" | "TooManyAttempts" | "IdInUse";
 
 type FileInfoForStream = {
     name: string,
-    size: number
+    size: number,
+    modified?: number|null,
+    hash?: string|null,
+    digest?: string|null,
+    encrypted?: boolean
 }
 
 type File = {
     id: Id,
     name: string,
-    size: number
+    size: number,
+    modified?: number|null,
+    hash?: string|null,
+    encrypted?: boolean
 };
 
 // Get a socket or use the cached one:
@@ -0,0 +1,166 @@
+// Checks the browser implementation of the encrypted transfer format against the
+// same known bytes as the tests in server/src/e2e.rs. Run with `npm test`.
+
+import { keyFromFragment, Key, Encryptor, Decryptor, encryptedSize, encryptName, decryptName } from "./e2e";
+
+const KEY_FRAGMENT = "key=AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8";
+const NONCE_PREFIX = new Uint8Array([0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6]);
+const CHUNK_SIZE = 4;
+const HEADER_LEN = 16;
+const SEALED_LEN = CHUNK_SIZE + 16;
+const HELLO_WORLD = "534532450100000004a0a1a2a3a4a5a6"
+    + "62b470c538444a878649886cd7275e51d16bcbfa"
+    + "3ec260a8211903e376f6980d9d640d25d1095b0b"
+    + "93c1e56618ca4836fa46a11958ffccfb769d25";
+const HELLO_WO = "534532450100000004a0a1a2a3a4a5a6"
+    + "62b470c538444a878649886cd7275e51d16bcbfa"
+    + "8626b786cabcf3057c4f51bc702623a98ea6f86a";
+const EMPTY = "534532450100000004a0a1a2a3a4a5a6"
+    + "fde53ba7b951af495281984e0e0b5f42";
+const CAFE_NAME = "EBESExQVFhcYGRobHp_-1eDnTsu-EVjXZhLoSyc76Ja38JnyCQ";
+
+const tests: [string, (key: Key) => Promise<void>][] = [
+
+    ["encrypts to known bytes", key => {
+        const cases: [string, string, number][] = [
+            ["hello world", HELLO_WORLD, 1],
+            ["hello world", HELLO_WORLD, 5],
+            ["hello world", HELLO_WORLD, 11],
+            ["hello wo", HELLO_WO, 3],
+            ["", EMPTY, 1]
+        ];
+        return sequence(cases.map(([plain, expected, pieces]) => () =>
+            encrypt(key, text(plain), pieces).then(out => {
+                assertEqual(toHex(out), expected, `encrypting "${plain}" in pieces of ${pieces}`);
+                assertEqual(encryptedSize(plain.length, CHUNK_SIZE), expected.length / 2, `encrypted size of "${plain}"`);
+            })
+        ));
+    }],
+
+    ["decrypts known bytes", key => sequence([
+        () => decrypt(key, fromHex(HELLO_WORLD)).then(out => assertEqual(fromText(out), "hello world", "hello world")),
+        () => decrypt(key, fromHex(HELLO_WO)).then(out => assertEqual(fromText(out), "hello wo", "hello wo")),
+        () => decrypt(key, fromHex(EMPTY)).then(out => assertEqual(fromText(out), "", "empty file"))
+    ])],
+
+    ["marks the last chunk", () => {
+        // A full chunk is the last one when nothing follows it, so "o wo" is
+        // sealed differently depending on whether "rld" comes after it:
+        const short = HELLO_WO.slice(0, 2 * (HEADER_LEN + SEALED_LEN));
+        assertEqual(short, HELLO_WORLD.slice(0, 2 * (HEADER_LEN + SEALED_LEN)), "first chunk");
+        assert(HELLO_WO.slice(short.length) !== HELLO_WORLD.slice(short.length, HELLO_WO.length), "last chunk is marked");
+        return Promise.resolve();
+    }],
+
+    ["rejects truncated files", key => {
+        const out = fromHex(HELLO_WORLD);
+        const lengths = [HEADER_LEN + 2 * SEALED_LEN, HEADER_LEN + SEALED_LEN, out.length - 1, HEADER_LEN, HEADER_LEN - 1];
+        return sequence(lengths.map(length => () =>
+            assertRejects(decrypt(key, out.slice(0, length)), `truncated to ${length} bytes`)
+        ));
+    }],
+
+    ["rejects reordered chunks", key => {
+        const out = fromHex(HELLO_WORLD);
+        const header = out.slice(0, HEADER_LEN);
+        const chunks = [0, 1, 2].map(n => out.slice(HEADER_LEN + n * SEALED_LEN, HEADER_LEN + (n + 1) * SEALED_LEN));
+        return sequence([
+            () => assertRejects(decrypt(key, concat([header, chunks[1], chunks[0], chunks[2]])), "first two swapped"),
+            () => assertRejects(decrypt(key, concat([header, chunks[2], chunks[0], chunks[1]])), "last chunk first")
+        ]);
+    }],
+
+    ["rejects tampered bytes", key => {
+        const flip = (at: number) => {
+            const out = fromHex(HELLO_WORLD);
+            out[at] ^= 1;
+            return out;
+        };
+        return sequence([
+            () => assertRejects(decrypt(key, flip(HEADER_LEN)), "ciphertext changed"),
+            () => assertRejects(decrypt(key, flip(8)), "chunk size changed"),
+            () => assertRejects(decrypt(key, flip(9)), "nonce prefix changed")
+        ]);
+    }],
+
+    ["decrypts known names", key =>
+        decryptName(key, CAFE_NAME)
+            .then(name => assertEqual(name, "café.txt", "known name"))
+            .then(() => encryptName(key, "café.txt"))
+            .then(encrypted => decryptName(key, encrypted))
+            .then(name => assertEqual(name, "café.txt", "round tripped name"))
+    ]
+
+];
+
+function encrypt(key: Key, plain: Uint8Array, pieces: number): Promise<Uint8Array> {
+    const e = new Encryptor(key, CHUNK_SIZE, NONCE_PREFIX);
+    const parts: Promise<Uint8Array>[] = [];
+    for(let i = 0; i < plain.length; i += pieces) {
+        parts.push(e.push(plain.slice(i, i + pieces)));
+    }
+    parts.push(e.finish());
+    return Promise.all(parts).then(concat);
+}
+
+function decrypt(key: Key, sealed: Uint8Array): Promise<Uint8Array> {
+    const d = new Decryptor(key);
+    return d.push(sealed).then(start => d.finish().then(end => concat([start, end])));
+}
+
+function sequence(steps: (() => Promise<void>)[]): Promise<void> {
+    return steps.reduce((prev, step) => prev.then(step), Promise.resolve());
+}
+
+function assert(ok: boolean, what: string) {
+    if(!ok) throw new Error(`Assertion failed: ${what}`);
+}
+
+function assertEqual<T>(actual: T, expected: T, what: string) {
+    if(actual !== expected) throw new Error(`${what}: expected ${expected}, got ${actual}`);
+}
+
+function assertRejects(p: Promise<any>, what: string): Promise<void> {
+    return p.then(
+        () => { throw new Error(`${what}: expected an error`) },
+        () => undefined
+    );
+}
+
+function text(s: string): Uint8Array {
+    return new TextEncoder().encode(s);
+}
+
+function fromText(bytes: Uint8Array): string {
+    return new TextDecoder().decode(bytes);
+}
+
+function toHex(bytes: Uint8Array): string {
+    return Array.prototype.map.call(bytes, (b: number) => (b < 16 ? "0" : "") + b.toString(16)).join("");
+}
+
+function fromHex(hex: string): Uint8Array {
+    const bytes = new Uint8Array(hex.length / 2);
+    for(let i = 0; i < bytes.length; i++) bytes[i] = parseInt(hex.slice(2 * i, 2 * i + 2), 16);
+    return bytes;
+}
+
+function concat(parts: Uint8Array[]): Uint8Array {
+    const out = new Uint8Array(parts.reduce((n, p) => n + p.length, 0));
+    let offset = 0;
+    for(const p of parts) {
+        out.set(p, offset);
+        offset += p.length;
+    }
+    return out;
+}
+
+keyFromFragment(KEY_FRAGMENT)
+    .then(key => {
+        if(!key) throw new Error("Can't read the test key");
+        return sequence(tests.map(([name, test]) => () => test(key).then(() => console.log(`ok: ${name}`))));
+    })
+    .catch(e => {
+        // Thrown outside of the promise so that node exits with an error:
+        setTimeout(() => { throw e });
+    });
@@ -0,0 +1,197 @@
+// End-to-end encryption of file names and contents. The key lives only in the
+// URL fragment, which browsers never send to the server, so the server only
+// ever relays ciphertext. See server/src/e2e.rs for a description of the format.
+
+const KEY_LEN = 32;
+const HEADER_LEN = 16;
+const TAG_LEN = 16;
+const NAME_NONCE_LEN = 12;
+const MAGIC = [0x53, 0x45, 0x32, 0x45]; // "SE2E"
+const VERSION = 1;
+const NAME_AAD = new Uint8Array([0x6e, 0x61, 0x6d, 0x65]); // "name"
+
+export const DEFAULT_CHUNK_SIZE = 64 * 1024;
+
+export type Key = CryptoKey;
+
+/** Read the key from the current URL fragment, if there is one */
+export function keyFromFragment(fragment: string): Promise<Key|null> {
+    const encoded = fragment.replace(/^#/, "")
+        .split("&")
+        .filter(part => part.indexOf("key=") === 0)
+        .map(part => part.slice("key=".length))[0];
+    if(!encoded) return Promise.resolve(null);
+
+    const bytes = fromBase64Url(encoded);
+    if(!bytes || bytes.length !== KEY_LEN) return Promise.resolve(null);
+    return importKey(bytes);
+}
+
+/** Make a new key, along with the URL fragment to share it in */
+export function generateKey(): Promise<{ key: Key, fragment: string }> {
+    const bytes = randomBytes(KEY_LEN);
+    return importKey(bytes).then(key => ({ key, fragment: `key=${toBase64Url(bytes)}` }));
+}
+
+/** How big a file of some size will be once encrypted */
+export function encryptedSize(size: number, chunkSize: number = DEFAULT_CHUNK_SIZE): number {
+    const chunks = Math.max(1, Math.ceil(size / chunkSize));
+    return HEADER_LEN + size + chunks * TAG_LEN;
+}
+
+export function encryptName(key: Key, name: string): Promise<string> {
+    const nonce = randomBytes(NAME_NONCE_LEN);
+    const data = new TextEncoder().encode(name);
+    return Promise.resolve(crypto.subtle.encrypt({ name: "AES-GCM", iv: nonce, additionalData: NAME_AAD }, key, data))
+        .then(ciphertext => toBase64Url(concat([nonce, new Uint8Array(ciphertext)])));
+}
+
+export function decryptName(key: Key, encrypted: string): Promise<string> {
+    const bytes = fromBase64Url(encrypted);
+    if(!bytes || bytes.length < NAME_NONCE_LEN + TAG_LEN) {
+        return Promise.reject(new Error("Encrypted name is invalid"));
+    }
+    const nonce = bytes.slice(0, NAME_NONCE_LEN);
+    const ciphertext = bytes.slice(NAME_NONCE_LEN);
+    return Promise.resolve(crypto.subtle.decrypt({ name: "AES-GCM", iv: nonce, additionalData: NAME_AAD }, key, ciphertext))
+        .then(name => new TextDecoder().decode(name));
+}
+
+/** Encrypt a file as it's read. Push bytes in as they come, and finish at the end */
+export class Encryptor {
+
+    private header: Uint8Array;
+    private headerSent = false;
+    private counter = 0;
+    private buffer = new Uint8Array(0);
+
+    // The nonce prefix must never be reused with the same key; it's only
+    // given by hand to check the output against known bytes.
+    constructor(private key: Key, private chunkSize: number = DEFAULT_CHUNK_SIZE, noncePrefix: Uint8Array = randomBytes(7)) {
+        this.header = new Uint8Array(HEADER_LEN);
+        this.header.set(MAGIC, 0);
+        this.header[4] = VERSION;
+        new DataView(this.header.buffer).setUint32(5, chunkSize);
+        this.header.set(noncePrefix, 9);
+    }
+
+    push(data: Uint8Array): Promise<Uint8Array> {
+        this.buffer = concat([this.buffer, data]);
+        const chunks: Promise<Uint8Array>[] = [Promise.resolve(this.takeHeader())];
+        // A full chunk is only sent once we know that it's not the last one:
+        while(this.buffer.length > this.chunkSize) {
+            chunks.push(this.seal(this.buffer.slice(0, this.chunkSize), false));
+            this.buffer = this.buffer.slice(this.chunkSize);
+        }
+        return Promise.all(chunks).then(concat);
+    }
+
+    finish(): Promise<Uint8Array> {
+        const header = this.takeHeader();
+        return this.seal(this.buffer, true).then(chunk => concat([header, chunk]));
+    }
+
+    private takeHeader(): Uint8Array {
+        if(this.headerSent) return new Uint8Array(0);
+        this.headerSent = true;
+        return this.header;
+    }
+
+    private seal(chunk: Uint8Array, last: boolean): Promise<Uint8Array> {
+        const iv = chunkNonce(this.header, this.counter++, last);
+        return Promise.resolve(crypto.subtle.encrypt({ name: "AES-GCM", iv, additionalData: this.header }, this.key, chunk))
+            .then(sealed => new Uint8Array(sealed));
+    }
+
+}
+
+/** Decrypt a file as it arrives. Push bytes in as they come, and finish at the end */
+export class Decryptor {
+
+    private header: Uint8Array|null = null;
+    private chunkSize = 0;
+    private counter = 0;
+    private buffer = new Uint8Array(0);
+
+    constructor(private key: Key) {}
+
+    push(data: Uint8Array): Promise<Uint8Array> {
+        this.buffer = concat([this.buffer, data]);
+        if(!this.header) {
+            if(this.buffer.length < HEADER_LEN) return Promise.resolve(new Uint8Array(0));
+            const header = this.buffer.slice(0, HEADER_LEN);
+            this.buffer = this.buffer.slice(HEADER_LEN);
+            if(MAGIC.some((b, i) => header[i] !== b)) return Promise.reject(new Error("Not an encrypted file"));
+            if(header[4] !== VERSION) return Promise.reject(new Error(`Unsupported encrypted file version ${header[4]}`));
+            this.chunkSize = new DataView(header.buffer).getUint32(5);
+            if(this.chunkSize === 0) return Promise.reject(new Error("Encrypted file has a chunk size of 0"));
+            this.header = header;
+        }
+        // Like when encrypting, a full chunk is only the last one if nothing follows it:
+        const sealedSize = this.chunkSize + TAG_LEN;
+        const chunks: Promise<Uint8Array>[] = [];
+        while(this.buffer.length > sealedSize) {
+            chunks.push(this.open(this.buffer.slice(0, sealedSize), false));
+            this.buffer = this.buffer.slice(sealedSize);
+        }
+        return Promise.all(chunks).then(concat);
+    }
+
+    finish(): Promise<Uint8Array> {
+        if(!this.header || this.buffer.length < TAG_LEN) {
+            return Promise.reject(new Error("Encrypted file is truncated"));
+        }
+        return this.open(this.buffer, true);
+    }
+
+    private open(chunk: Uint8Array, last: boolean): Promise<Uint8Array> {
+        const header = this.header as Uint8Array;
+        const iv = chunkNonce(header, this.counter++, last);
+        return Promise.resolve(crypto.subtle.decrypt({ name: "AES-GCM", iv, additionalData: header }, this.key, chunk))
+            .then(plain => new Uint8Array(plain));
+    }
+
+}
+
+function chunkNonce(header: Uint8Array, counter: number, last: boolean): Uint8Array {
+    const nonce = new Uint8Array(12);
+    nonce.set(header.slice(9, 16), 0);
+    new DataView(nonce.buffer).setUint32(7, counter);
+    nonce[11] = last ? 1 : 0;
+    return nonce;
+}
+
+function importKey(bytes: Uint8Array): Promise<Key> {
+    return Promise.resolve(crypto.subtle.importKey("raw", bytes, { name: "AES-GCM" }, false, ["encrypt", "decrypt"]));
+}
+
+function randomBytes(n: number): Uint8Array {
+    return crypto.getRandomValues(new Uint8Array(n)) as Uint8Array;
+}
+
+function concat(parts: Uint8Array[]): Uint8Array {
+    const out = new Uint8Array(parts.reduce((n, p) => n + p.length, 0));
+    let offset = 0;
+    for(const p of parts) {
+        out.set(p, offset);
+        offset += p.length;
+    }
+    return out;
+}
+
+function toBase64Url(bytes: Uint8Array): string {
+    let s = "";
+    for(let i = 0; i < bytes.length; i++) s += String.fromCharCode(bytes[i]);
+    return btoa(s).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+function fromBase64Url(s: string): Uint8Array|null {
+    try {
+        const bin = atob(s.replace(/-/g, "+").replace(/_/g, "/"));
+        const bytes = new Uint8Array(bin.length);
+        for(let i = 0; i < bin.length; i++) bytes[i] = bin.charCodeAt(i);
+        return bytes;
+    } catch(e) {
+        return null;
+    }
+}
@@ -5,7 +5,9 @@ if(!document.location) {
     throw Error("document.location is null");
 }
 
-const BASE_URL = `ws://${document.location.host}`;
+// Use secure websockets if the page itself came over HTTPS, since browsers won't allow otherwise:
+const WS_PROTOCOL = document.location.protocol === "https:" ? "wss" : "ws";
+const BASE_URL = `${WS_PROTOCOL}://${document.location.host}`;
 
 function MakeWebSocket(): WebSocket {
     const type = Mode.isSender ? "sender" : "receiver";
@@ -0,0 +1,648 @@
+use std::collections::{HashSet,VecDeque};
+use std::str::FromStr;
+use futures::{Async, Future, Poll, Stream, try_ready};
+use crate::id::Id;
+use crate::messages::FileInfoForStream;
+use crate::Err;
+
+/// Which files to put in an archive, and what sort of archive to make. This
+/// is parsed from the last segment of the archive URL, eg "all.zip" or
+/// "<file_id>,<file_id>.zip", which also gives browsers a sensible name to
+/// save the archive as.
+pub struct ArchiveRequest {
+    pub files: FileSelection,
+    pub kind: Kind
+}
+
+pub enum FileSelection {
+    All,
+    Only(Vec<Id>)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Zip,
+    Tar,
+    TarGz
+}
+
+impl Kind {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Kind::Zip => "application/zip",
+            Kind::Tar => "application/x-tar",
+            Kind::TarGz => "application/gzip"
+        }
+    }
+    fn from_extension(ext: &str) -> Option<Kind> {
+        match ext {
+            "zip" => Some(Kind::Zip),
+            "tar" => Some(Kind::Tar),
+            "tar.gz" | "tgz" => Some(Kind::TarGz),
+            _ => None
+        }
+    }
+}
+
+impl FromStr for ArchiveRequest {
+    type Err = Err;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Ids never contain '.', so the first one separates files from extension:
+        let mut parts = s.splitn(2, '.');
+        let files = parts.next().unwrap_or("");
+        let ext = parts.next().unwrap_or("");
+
+        let kind = Kind::from_extension(ext)
+            .ok_or_else(|| Err::new(format!("Unknown archive type: {}", ext)))?;
+
+        let files = if files == "all" {
+            FileSelection::All
+        } else {
+            let ids = files.split(',')
+                .map(|id| id.parse())
+                .collect::<Result<Vec<Id>,_>>()
+                .map_err(|e| Err::new(format!("Invalid file id: {}", e)))?;
+            FileSelection::Only(ids)
+        };
+
+        Ok(ArchiveRequest { files, kind })
+    }
+}
+
+/// An archive format that can be written out on the fly, a file at a time,
+/// without knowing the contents of a file until it has been streamed.
+pub trait Format: Send + 'static {
+    /// Bytes to write before the contents of a file. `modified` is in milliseconds
+    /// since the unix epoch, if the sender told us when the file was last modified:
+    fn file_start(&mut self, name: &str, size: u64, modified: Option<u64>) -> Vec<u8>;
+    /// Called with each chunk of file contents, handing back what to write:
+    fn file_data(&mut self, data: Vec<u8>) -> Vec<u8>;
+    /// Bytes to write after the contents of a file:
+    fn file_end(&mut self) -> Result<Vec<u8>, Err>;
+    /// Bytes to write once every file has been written:
+    fn finish(&mut self) -> Vec<u8>;
+}
+
+/// A stream of archive bytes. Files are requested one at a time using the
+/// `fetch` function, and their contents are written into the archive as
+/// they arrive, so we never hold more than a chunk of any file in memory.
+pub struct Archive<F, G, Fut, S> {
+    format: F,
+    files: VecDeque<Id>,
+    fetch: G,
+    names: HashSet<String>,
+    state: ArchiveState<Fut, S>
+}
+
+enum ArchiveState<Fut, S> {
+    Idle,
+    Requesting(Fut),
+    Streaming(S),
+    Done
+}
+
+impl <F, G, Fut, S> Archive<F, G, Fut, S>
+    where
+        F: Format,
+        G: FnMut(Id) -> Fut,
+        Fut: Future<Item = (FileInfoForStream, S), Error = Err>,
+        S: Stream<Item = Vec<u8>, Error = Err>
+{
+    pub fn new(format: F, files: Vec<Id>, fetch: G) -> Archive<F, G, Fut, S> {
+        Archive {
+            format,
+            files: files.into_iter().collect(),
+            fetch,
+            names: HashSet::new(),
+            state: ArchiveState::Idle
+        }
+    }
+
+    // Names come from the sender, so strip anything that would let them escape
+    // the folder the archive is extracted into, and make sure each is unique:
+    fn entry_name(&mut self, name: &str) -> String {
+        let base: String = name
+            .chars()
+            .map(|c| if c == '/' || c == '\\' || c.is_control() { '_' } else { c })
+            .collect();
+        let base = match base.trim_start_matches('.') {
+            "" => "file".to_owned(),
+            b => b.to_owned()
+        };
+
+        let mut candidate = base.clone();
+        let mut n = 1;
+        while self.names.contains(&candidate) {
+            candidate = match base.rfind('.') {
+                Some(idx) => format!("{} ({}){}", &base[..idx], n, &base[idx..]),
+                None => format!("{} ({})", base, n)
+            };
+            n += 1;
+        }
+        self.names.insert(candidate.clone());
+        candidate
+    }
+}
+
+impl <F, G, Fut, S> Stream for Archive<F, G, Fut, S>
+    where
+        F: Format,
+        G: FnMut(Id) -> Fut,
+        Fut: Future<Item = (FileInfoForStream, S), Error = Err>,
+        S: Stream<Item = Vec<u8>, Error = Err>
+{
+    type Item = Vec<u8>;
+    type Error = Err;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, Err> {
+        loop {
+            let next = match &mut self.state {
+                ArchiveState::Idle => {
+                    match self.files.pop_front() {
+                        Some(file_id) => ArchiveState::Requesting((self.fetch)(file_id)),
+                        None => {
+                            self.state = ArchiveState::Done;
+                            return Ok(Async::Ready(Some(self.format.finish())));
+                        }
+                    }
+                },
+                ArchiveState::Requesting(fut) => {
+                    let (info, data) = try_ready!(fut.poll());
+                    let name = self.entry_name(&info.name);
+                    self.state = ArchiveState::Streaming(data);
+                    return Ok(Async::Ready(Some(self.format.file_start(&name, info.size, info.modified))));
+                },
+                ArchiveState::Streaming(data) => {
+                    match try_ready!(data.poll()) {
+                        Some(chunk) => {
+                            return Ok(Async::Ready(Some(self.format.file_data(chunk))));
+                        },
+                        None => {
+                            self.state = ArchiveState::Idle;
+                            return Ok(Async::Ready(Some(self.format.file_end()?)));
+                        }
+                    }
+                },
+                ArchiveState::Done => {
+                    return Ok(Async::Ready(None));
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+
+/// Uncompressed ZIP64 archives. We don't know the CRC of a file until we've
+/// streamed it, so each entry is followed by a data descriptor, and we always
+/// use ZIP64 fields so that files bigger than 4GB are fine.
+pub struct Zip {
+    offset: u64,
+    current: Option<ZipEntry>,
+    entries: Vec<ZipEntry>
+}
+
+struct ZipEntry {
+    name: String,
+    offset: u64,
+    size: u64,
+    crc: crc32fast::Hasher,
+    crc_value: u32
+}
+
+// ZIP64 needs version 4.5. The flags say that sizes and CRC come after the
+// file data, and that the name is UTF-8:
+const ZIP_VERSION: u16 = 45;
+const ZIP_FLAGS: u16 = 0x0008 | 0x0800;
+// 1980-01-01 00:00, the earliest date a zip file can express:
+const ZIP_TIME: u16 = 0;
+const ZIP_DATE: u16 = (1 << 5) | 1;
+
+impl Zip {
+    pub fn new() -> Zip {
+        Zip { offset: 0, current: None, entries: Vec::new() }
+    }
+    fn written(&mut self, bytes: Vec<u8>) -> Vec<u8> {
+        self.offset += bytes.len() as u64;
+        bytes
+    }
+}
+
+impl Format for Zip {
+    fn file_start(&mut self, name: &str, _size: u64, _modified: Option<u64>) -> Vec<u8> {
+        // Names can be at most 65535 bytes long, so anything longer is cut short:
+        let name = truncate_utf8(name, u16::max_value() as usize);
+
+        let mut b = Vec::with_capacity(50 + name.len());
+        put_u32(&mut b, 0x04034b50);
+        put_u16(&mut b, ZIP_VERSION);
+        put_u16(&mut b, ZIP_FLAGS);
+        put_u16(&mut b, 0); // stored; no compression
+        put_u16(&mut b, ZIP_TIME);
+        put_u16(&mut b, ZIP_DATE);
+        put_u32(&mut b, 0); // crc is in the data descriptor
+        put_u32(&mut b, 0xFFFFFFFF); // sizes are in the zip64 field..
+        put_u32(&mut b, 0xFFFFFFFF);
+        put_u16(&mut b, name.len() as u16);
+        put_u16(&mut b, 20);
+        b.extend_from_slice(name.as_bytes());
+        // ..which are in turn in the data descriptor:
+        put_u16(&mut b, 0x0001);
+        put_u16(&mut b, 16);
+        put_u64(&mut b, 0);
+        put_u64(&mut b, 0);
+
+        self.current = Some(ZipEntry {
+            name: name.to_owned(),
+            offset: self.offset,
+            size: 0,
+            crc: crc32fast::Hasher::new(),
+            crc_value: 0
+        });
+        self.written(b)
+    }
+    fn file_data(&mut self, data: Vec<u8>) -> Vec<u8> {
+        if let Some(entry) = &mut self.current {
+            entry.size += data.len() as u64;
+            entry.crc.update(&data);
+        }
+        self.offset += data.len() as u64;
+        data
+    }
+    fn file_end(&mut self) -> Result<Vec<u8>, Err> {
+        let mut entry = match self.current.take() {
+            Some(entry) => entry,
+            None => return Ok(Vec::new())
+        };
+        entry.crc_value = std::mem::replace(&mut entry.crc, crc32fast::Hasher::new()).finalize();
+
+        let mut b = Vec::with_capacity(24);
+        put_u32(&mut b, 0x08074b50);
+        put_u32(&mut b, entry.crc_value);
+        put_u64(&mut b, entry.size);
+        put_u64(&mut b, entry.size);
+
+        self.entries.push(entry);
+        Ok(self.written(b))
+    }
+    fn finish(&mut self) -> Vec<u8> {
+        let mut b = Vec::new();
+        let cd_offset = self.offset;
+
+        // Central directory:
+        for entry in &self.entries {
+            put_u32(&mut b, 0x02014b50);
+            put_u16(&mut b, ZIP_VERSION);
+            put_u16(&mut b, ZIP_VERSION);
+            put_u16(&mut b, ZIP_FLAGS);
+            put_u16(&mut b, 0);
+            put_u16(&mut b, ZIP_TIME);
+            put_u16(&mut b, ZIP_DATE);
+            put_u32(&mut b, entry.crc_value);
+            put_u32(&mut b, 0xFFFFFFFF);
+            put_u32(&mut b, 0xFFFFFFFF);
+            put_u16(&mut b, entry.name.len() as u16);
+            put_u16(&mut b, 28);
+            put_u16(&mut b, 0); // comment length
+            put_u16(&mut b, 0); // disk number
+            put_u16(&mut b, 0); // internal attributes
+            put_u32(&mut b, 0); // external attributes
+            put_u32(&mut b, 0xFFFFFFFF); // offset is in the zip64 field
+            b.extend_from_slice(entry.name.as_bytes());
+            put_u16(&mut b, 0x0001);
+            put_u16(&mut b, 24);
+            put_u64(&mut b, entry.size);
+            put_u64(&mut b, entry.size);
+            put_u64(&mut b, entry.offset);
+        }
+
+        let cd_size = b.len() as u64;
+        let zip64_eocd_offset = cd_offset + cd_size;
+        let count = self.entries.len() as u64;
+
+        // Zip64 end of central directory record:
+        put_u32(&mut b, 0x06064b50);
+        put_u64(&mut b, 44);
+        put_u16(&mut b, ZIP_VERSION);
+        put_u16(&mut b, ZIP_VERSION);
+        put_u32(&mut b, 0);
+        put_u32(&mut b, 0);
+        put_u64(&mut b, count);
+        put_u64(&mut b, count);
+        put_u64(&mut b, cd_size);
+        put_u64(&mut b, cd_offset);
+
+        // Zip64 end of central directory locator:
+        put_u32(&mut b, 0x07064b50);
+        put_u32(&mut b, 0);
+        put_u64(&mut b, zip64_eocd_offset);
+        put_u32(&mut b, 1);
+
+        // End of central directory record, pointing at the zip64 one:
+        put_u32(&mut b, 0x06054b50);
+        put_u16(&mut b, 0);
+        put_u16(&mut b, 0);
+        put_u16(&mut b, 0xFFFF);
+        put_u16(&mut b, 0xFFFF);
+        put_u32(&mut b, 0xFFFFFFFF);
+        put_u32(&mut b, 0xFFFFFFFF);
+        put_u16(&mut b, 0);
+
+        self.written(b)
+    }
+}
+
+/// POSIX (pax) tar archives. Tar headers need to know the size of a file up
+/// front, so we trust the size the sender gives us. Anything past that size is
+/// dropped, and if a file comes up short the archive can't be finished.
+pub struct Tar {
+    declared: u64,
+    written: u64
+}
+
+const TAR_BLOCK: usize = 512;
+
+impl Tar {
+    pub fn new() -> Tar {
+        Tar { declared: 0, written: 0 }
+    }
+}
+
+impl Format for Tar {
+    fn file_start(&mut self, name: &str, size: u64, modified: Option<u64>) -> Vec<u8> {
+        self.declared = size;
+        self.written = 0;
+
+        // Tar wants seconds. If we don't know when the file was modified, now will do:
+        let mtime = modified.map(|ms| ms / 1000).unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        });
+
+        let mut b = Vec::with_capacity(TAR_BLOCK * 3);
+
+        // Long or non-ASCII names don't fit in a ustar header, so put
+        // them in a pax extended header in front of the real one:
+        if name.len() > 100 || !name.is_ascii() {
+            let record = pax_record("path", name);
+            b.extend(tar_header("././@PaxHeader", record.len() as u64, mtime, b'x'));
+            b.extend_from_slice(record.as_bytes());
+            b.extend(tar_padding(record.len() as u64));
+        }
+
+        let short_name: String = name.chars().filter(|c| c.is_ascii()).take(100).collect();
+        b.extend(tar_header(&short_name, size, mtime, b'0'));
+        b
+    }
+    fn file_data(&mut self, mut data: Vec<u8>) -> Vec<u8> {
+        let remaining = self.declared - self.written;
+        if data.len() as u64 > remaining {
+            data.truncate(remaining as usize);
+        }
+        self.written += data.len() as u64;
+        data
+    }
+    fn file_end(&mut self) -> Result<Vec<u8>, Err> {
+        if self.written < self.declared {
+            return Err(Err::new(format!("Expected {} bytes but only got {}", self.declared, self.written)));
+        }
+        Ok(tar_padding(self.declared))
+    }
+    fn finish(&mut self) -> Vec<u8> {
+        vec![0; TAR_BLOCK * 2]
+    }
+}
+
+fn tar_header(name: &str, size: u64, mtime: u64, kind: u8) -> Vec<u8> {
+    let mut h = vec![0u8; TAR_BLOCK];
+    h[..name.len()].copy_from_slice(name.as_bytes());
+    put_octal(&mut h[100..108], 0o644);
+    put_octal(&mut h[108..116], 0);
+    put_octal(&mut h[116..124], 0);
+    // Sizes too big for the octal field use the base-256 encoding
+    // understood by GNU tar, bsdtar and friends:
+    if size >= 0o77777777777 {
+        h[124] = 0x80;
+        h[128..136].copy_from_slice(&size.to_be_bytes());
+    } else {
+        put_octal(&mut h[124..136], size);
+    }
+    put_octal(&mut h[136..148], mtime);
+    h[156] = kind;
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+
+    // The checksum is worked out as if the checksum field was all spaces:
+    for b in &mut h[148..156] { *b = b' ' }
+    let checksum: u32 = h.iter().map(|&b| b as u32).sum();
+    put_octal(&mut h[148..155], checksum as u64);
+    h
+}
+
+// Write a zero padded, NUL terminated octal number into a header field:
+fn put_octal(field: &mut [u8], n: u64) {
+    let digits = format!("{:0width$o}", n, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+// Tar entries are padded out to a whole number of blocks:
+fn tar_padding(size: u64) -> Vec<u8> {
+    let rem = (size % TAR_BLOCK as u64) as usize;
+    if rem == 0 { Vec::new() } else { vec![0; TAR_BLOCK - rem] }
+}
+
+// A pax record is "<length> <key>=<value>\n", where the length includes itself:
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = format!(" {}={}\n", key, value);
+    let mut len = rest.len() + 1;
+    while format!("{}", len).len() + rest.len() != len {
+        len += 1;
+    }
+    format!("{}{}", len, rest)
+}
+
+// The longest start of `s` that fits in `max` bytes without splitting a character:
+fn truncate_utf8(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+fn put_u16(b: &mut Vec<u8>, n: u16) {
+    b.extend_from_slice(&n.to_le_bytes());
+}
+fn put_u32(b: &mut Vec<u8>, n: u32) {
+    b.extend_from_slice(&n.to_le_bytes());
+}
+fn put_u64(b: &mut Vec<u8>, n: u64) {
+    b.extend_from_slice(&n.to_le_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use futures::{future, stream};
+    use crate::id::IdGen;
+
+    type Data = Box<dyn Stream<Item = Vec<u8>, Error = Err> + Send>;
+
+    fn file(name: &str, size: u64, data: Data) -> (FileInfoForStream, Data) {
+        modified_file(name, size, None, data)
+    }
+
+    fn modified_file(name: &str, size: u64, modified: Option<u64>, data: Data) -> (FileInfoForStream, Data) {
+        let info = FileInfoForStream {
+            name: name.to_owned(),
+            size,
+            modified,
+            hash: None,
+            digest: None,
+            encrypted: false
+        };
+        (info, data)
+    }
+
+    fn chunks(chunks: &[&str]) -> Data {
+        let chunks: Vec<Vec<u8>> = chunks.iter().map(|c| c.as_bytes().to_vec()).collect();
+        Box::new(stream::iter_ok(chunks))
+    }
+
+    // An archive of the given files, which are handed out in order:
+    fn archive<F: Format>(format: F, files: Vec<(FileInfoForStream, Data)>) -> impl Stream<Item = Vec<u8>, Error = Err> {
+        let mut ids = IdGen::new();
+        let file_ids = files.iter().map(|_| ids.make_id()).collect();
+        let mut files: VecDeque<_> = files.into_iter().collect();
+        Archive::new(format, file_ids, move |_| future::ok(files.pop_front().unwrap()))
+    }
+
+    fn archive_bytes<F: Format>(format: F, files: Vec<(FileInfoForStream, Data)>) -> Vec<u8> {
+        archive(format, files).concat2().wait().unwrap()
+    }
+
+    #[test]
+    fn zip_round_trip() {
+        let bytes = archive_bytes(Zip::new(), vec![
+            file("hello.txt", 11, chunks(&["hello", " ", "world"])),
+            file("empty", 0, chunks(&[])),
+            file("café.txt", 4, chunks(&["ab", "cd"])),
+            file("hello.txt", 2, chunks(&["hi"]))
+        ]);
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let expected = [
+            ("hello.txt", "hello world"),
+            ("empty", ""),
+            ("café.txt", "abcd"),
+            ("hello (1).txt", "hi")
+        ];
+        assert_eq!(zip.len(), expected.len());
+        for (i, (name, contents)) in expected.iter().enumerate() {
+            // Reading to the end checks the CRC too:
+            let mut entry = zip.by_index(i).unwrap();
+            let mut s = String::new();
+            entry.read_to_string(&mut s).unwrap();
+            assert_eq!(entry.name(), *name);
+            assert_eq!(s, *contents);
+        }
+    }
+
+    #[test]
+    fn zip_names_are_capped() {
+        let long = "é".repeat(40000);
+        let bytes = archive_bytes(Zip::new(), vec![
+            file(&long, 2, chunks(&["hi"]))
+        ]);
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut entry = zip.by_index(0).unwrap();
+        let mut s = String::new();
+        entry.read_to_string(&mut s).unwrap();
+        assert_eq!(entry.name(), &long[..65534]);
+        assert_eq!(s, "hi");
+    }
+
+    // This streams more than 4GB through the archiver, so it's slow; run it with `--ignored`:
+    #[test]
+    #[ignore]
+    fn zip_bigger_than_4gb() {
+        const CHUNK: usize = 1024 * 1024;
+        const BIG: u64 = 4 * 1024 * 1024 * 1024 + CHUNK as u64;
+
+        // A file of zeros that's too big for the 32 bit sizes and offsets
+        // in a plain zip file, followed by a small one:
+        let zeros = stream::repeat(vec![0u8; CHUNK]).take(BIG / CHUNK as u64);
+        let files = vec![
+            file("big", BIG, Box::new(zeros)),
+            file("small.txt", 5, chunks(&["small"]))
+        ];
+
+        // Skip over the zeros rather than writing them, so the file on disk
+        // is sparse and we don't need 4GB of space to run this:
+        let path = std::env::temp_dir().join(format!("file_streamer_zip64_{}.zip", std::process::id()));
+        let mut out = fs::File::create(&path).unwrap();
+        for chunk in archive(Zip::new(), files).wait() {
+            let chunk = chunk.unwrap();
+            if chunk.len() == CHUNK {
+                out.seek(SeekFrom::Current(CHUNK as i64)).unwrap();
+            } else {
+                out.write_all(&chunk).unwrap();
+            }
+        }
+        drop(out);
+
+        let mut zip = zip::ZipArchive::new(fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(zip.by_name("big").unwrap().size(), BIG);
+        let mut small = zip.by_name("small.txt").unwrap();
+        assert!(small.header_start() > BIG);
+        let mut s = String::new();
+        small.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "small");
+
+        drop(small);
+        drop(zip);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tar_round_trip() {
+        let long = format!("{}.txt", "a".repeat(120));
+        let bytes = archive_bytes(Tar::new(), vec![
+            modified_file("hello.txt", 11, Some(1_600_000_000_123), chunks(&["hello", " ", "world"])),
+            modified_file("café.txt", 4, Some(1_500_000_000_000), chunks(&["ab", "cd"])),
+            modified_file(&long, 3, Some(1_400_000_000_999), chunks(&["abc"])),
+            file("empty", 0, chunks(&[]))
+        ]);
+        assert_eq!(bytes.len() % TAR_BLOCK, 0);
+
+        // Long and non-ASCII names come from the pax headers:
+        let expected = [
+            ("hello.txt", "hello world", Some(1_600_000_000)),
+            ("café.txt", "abcd", Some(1_500_000_000)),
+            (long.as_str(), "abc", Some(1_400_000_000)),
+            ("empty", "", None)
+        ];
+        let mut tar = tar::Archive::new(Cursor::new(bytes));
+        let mut count = 0;
+        for (entry, (name, contents, mtime)) in tar.entries().unwrap().zip(expected.iter()) {
+            let mut entry = entry.unwrap();
+            let mut s = String::new();
+            entry.read_to_string(&mut s).unwrap();
+            assert_eq!(entry.path().unwrap().to_str().unwrap(), *name);
+            assert_eq!(s, *contents);
+            if let Some(mtime) = mtime {
+                assert_eq!(entry.header().mtime().unwrap(), *mtime);
+            }
+            count += 1;
+        }
+        assert_eq!(count, expected.len());
+    }
+}
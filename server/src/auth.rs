@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use crate::id::Id;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const PBKDF2_ROUNDS: u32 = 100_000;
+
+/// A salted hash of the password protecting a sender's share. We never keep
+/// hold of the password itself.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PasswordHash {
+    salt: [u8; 16],
+    hash: [u8; 32]
+}
+
+impl PasswordHash {
+    pub fn new(password: &str) -> PasswordHash {
+        let mut salt = [0; 16];
+        rand::thread_rng().fill(&mut salt);
+        PasswordHash { salt, hash: hash_password(password, &salt) }
+    }
+    pub fn verify(&self, password: &str) -> bool {
+        constant_time_eq(&hash_password(password, &self.salt), &self.hash)
+    }
+}
+
+fn hash_password(password: &str, salt: &[u8]) -> [u8; 32] {
+    let mut hash = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, PBKDF2_ROUNDS, &mut hash);
+    hash
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// A secret handed to senders and receivers when they handshake, which they
+/// need to give back to reclaim their ID if they reconnect.
+pub fn reconnect_token() -> String {
+    let mut token = [0; 32];
+    rand::thread_rng().fill(&mut token);
+    base64::encode_config(&token, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn reconnect_token_matches(given: Option<&str>, expected: &str) -> bool {
+    given.map(|given| constant_time_eq(given.as_bytes(), expected.as_bytes())).unwrap_or(false)
+}
+
+/// Receivers that know the password for a share are given a token to download
+/// its files with. A token is only good for one sender, until it expires, and
+/// for as long as the sender keeps the same password. It's signed with a key
+/// that's made each time the server starts, so we don't need to store tokens.
+pub struct Tokens {
+    key: [u8; 32],
+    ttl: Duration
+}
+
+impl Tokens {
+    pub fn new(ttl: Duration) -> Tokens {
+        let mut key = [0; 32];
+        rand::thread_rng().fill(&mut key);
+        Tokens { key, ttl }
+    }
+    pub fn issue(&self, sender_id: Id, password: &PasswordHash) -> String {
+        let expires = unix_secs(SystemTime::now() + self.ttl);
+        let mut token = expires.to_be_bytes().to_vec();
+        token.extend_from_slice(&self.mac(sender_id, password, expires).finalize().into_bytes());
+        base64::encode_config(&token, base64::URL_SAFE_NO_PAD)
+    }
+    pub fn verify(&self, token: &str, sender_id: Id, password: &PasswordHash) -> bool {
+        let token = match base64::decode_config(token, base64::URL_SAFE_NO_PAD) {
+            Ok(token) => token,
+            Err(_) => return false
+        };
+        if token.len() != 8 + 32 { return false }
+        let mut expires = [0; 8];
+        expires.copy_from_slice(&token[..8]);
+        let expires = u64::from_be_bytes(expires);
+        expires > unix_secs(SystemTime::now())
+            && self.mac(sender_id, password, expires).verify_slice(&token[8..]).is_ok()
+    }
+    fn mac(&self, sender_id: Id, password: &PasswordHash, expires: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(b"download");
+        mac.update(sender_id.as_bytes());
+        mac.update(&password.salt);
+        mac.update(&expires.to_be_bytes());
+        mac
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Keeps track of failed password attempts for each sender, so that nobody can try
+/// passwords faster than `max_failures` per `window` from one address, or faster than
+/// `max_share_failures` per `window` from lots of them.
+pub struct Attempts {
+    failures: Mutex<HashMap<(Option<IpAddr>, Id), Failures>>,
+    share_failures: Mutex<HashMap<Id, Failures>>,
+    max_failures: u32,
+    max_share_failures: u32,
+    window: Duration
+}
+
+struct Failures {
+    count: u32,
+    since: Instant
+}
+
+impl Failures {
+    fn new() -> Failures {
+        Failures { count: 0, since: Instant::now() }
+    }
+    // Start counting afresh once the window is up, and say whether there's room for another:
+    fn allows(&mut self, max: u32, window: Duration) -> bool {
+        if self.since.elapsed() >= window {
+            *self = Failures::new();
+        }
+        self.count < max
+    }
+}
+
+impl Attempts {
+    pub fn new(max_failures: u32, max_share_failures: u32, window: Duration) -> Attempts {
+        Attempts {
+            failures: Mutex::new(HashMap::new()),
+            share_failures: Mutex::new(HashMap::new()),
+            max_failures,
+            max_share_failures,
+            window
+        }
+    }
+    /// Can this address have another go at the password for this sender? If so, the go
+    /// counts as a failure until `succeeded` says otherwise, so that guesses that are
+    /// checked at the same time can't get past the limits between them.
+    pub fn attempt(&self, addr: Option<IpAddr>, sender_id: Id) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        let mut share_failures = self.share_failures.lock().unwrap();
+        let f = failures.entry((addr, sender_id)).or_insert_with(Failures::new);
+        let s = share_failures.entry(sender_id).or_insert_with(Failures::new);
+        if !f.allows(self.max_failures, self.window) || !s.allows(self.max_share_failures, self.window) {
+            return false
+        }
+        f.count += 1;
+        s.count += 1;
+        true
+    }
+    pub fn succeeded(&self, addr: Option<IpAddr>, sender_id: Id) {
+        let mut failures = self.failures.lock().unwrap();
+        let mut share_failures = self.share_failures.lock().unwrap();
+        failures.remove(&(addr, sender_id));
+        if let Some(s) = share_failures.get_mut(&sender_id) {
+            s.count = s.count.saturating_sub(1);
+        }
+    }
+    /// Forget about failures that no longer count against anybody:
+    pub fn reap(&self) {
+        let window = self.window;
+        self.failures.lock().unwrap().retain(|_, f| f.since.elapsed() < window);
+        self.share_failures.lock().unwrap().retain(|_, f| f.since.elapsed() < window);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+    use crate::id::IdGen;
+
+    #[test]
+    fn password_hash_verifies() {
+        let hash = PasswordHash::new("correct horse");
+        assert!(hash.verify("correct horse"));
+        assert!(!hash.verify("Correct horse"));
+        assert!(!hash.verify("correct horse "));
+        assert!(!hash.verify(""));
+        // Each hash is salted differently:
+        assert!(PasswordHash::new("correct horse") != hash);
+    }
+
+    #[test]
+    fn reconnect_tokens_match() {
+        let token = reconnect_token();
+        assert!(reconnect_token_matches(Some(token.as_str()), &token));
+        assert!(!reconnect_token_matches(Some(&token[1..]), &token));
+        assert!(!reconnect_token_matches(None, &token));
+        assert!(reconnect_token() != token);
+    }
+
+    #[test]
+    fn tokens_verify() {
+        let mut ids = IdGen::new();
+        let (sender, other_sender) = (ids.make_id(), ids.make_id());
+        let password = PasswordHash::new("secret");
+        let tokens = Tokens::new(Duration::from_secs(60));
+
+        let token = tokens.issue(sender, &password);
+        assert!(tokens.verify(&token, sender, &password));
+        // A token is only good for the sender it was issued for, with the same password:
+        assert!(!tokens.verify(&token, other_sender, &password));
+        assert!(!tokens.verify(&token, sender, &PasswordHash::new("secret")));
+        // And only from the server that issued it:
+        assert!(!Tokens::new(Duration::from_secs(60)).verify(&token, sender, &password));
+    }
+
+    #[test]
+    fn tokens_expire() {
+        let sender = IdGen::new().make_id();
+        let password = PasswordHash::new("secret");
+        let tokens = Tokens::new(Duration::from_secs(0));
+        assert!(!tokens.verify(&tokens.issue(sender, &password), sender, &password));
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let sender = IdGen::new().make_id();
+        let password = PasswordHash::new("secret");
+        let tokens = Tokens::new(Duration::from_secs(60));
+        let token = base64::decode_config(&tokens.issue(sender, &password), base64::URL_SAFE_NO_PAD).unwrap();
+
+        // Pushing the expiry back, or changing the signature, breaks the signature:
+        for at in &[0, 7, 8, token.len() - 1] {
+            let mut tampered = token.clone();
+            tampered[*at] ^= 1;
+            let tampered = base64::encode_config(&tampered, base64::URL_SAFE_NO_PAD);
+            assert!(!tokens.verify(&tampered, sender, &password), "byte {} changed", at);
+        }
+        let truncated = base64::encode_config(&token[..token.len() - 1], base64::URL_SAFE_NO_PAD);
+        assert!(!tokens.verify(&truncated, sender, &password));
+        assert!(!tokens.verify("not base64!", sender, &password));
+        assert!(!tokens.verify("", sender, &password));
+    }
+
+    #[test]
+    fn attempts_are_limited_per_address() {
+        let sender = IdGen::new().make_id();
+        let (a, b) = (Some("10.0.0.1".parse().unwrap()), Some("10.0.0.2".parse().unwrap()));
+        let attempts = Attempts::new(2, 100, Duration::from_millis(200));
+
+        assert!(attempts.attempt(a, sender));
+        assert!(attempts.attempt(a, sender));
+        assert!(!attempts.attempt(a, sender));
+        // Other addresses have their own limit:
+        assert!(attempts.attempt(b, sender));
+        // Getting it right wipes the slate clean:
+        attempts.succeeded(b, sender);
+        assert!(attempts.attempt(b, sender));
+        assert!(attempts.attempt(b, sender));
+        assert!(!attempts.attempt(b, sender));
+    }
+
+    #[test]
+    fn attempts_are_limited_per_share() {
+        let mut ids = IdGen::new();
+        let (sender, other_sender) = (ids.make_id(), ids.make_id());
+        let attempts = Attempts::new(100, 3, Duration::from_millis(200));
+        let addr = |n: u8| Some(IpAddr::from([10, 0, 0, n]));
+
+        for n in 0..3 {
+            assert!(attempts.attempt(addr(n), sender));
+        }
+        // Lots of addresses can't get round the limit between them...
+        assert!(!attempts.attempt(addr(3), sender));
+        // ...but it only applies to this share:
+        assert!(attempts.attempt(addr(3), other_sender));
+        // Guesses that turn out right don't count against the share:
+        attempts.succeeded(addr(0), sender);
+        assert!(attempts.attempt(addr(4), sender));
+        assert!(!attempts.attempt(addr(5), sender));
+    }
+
+    #[test]
+    fn attempts_window_resets() {
+        let sender = IdGen::new().make_id();
+        let attempts = Attempts::new(1, 100, Duration::from_millis(100));
+
+        assert!(attempts.attempt(None, sender));
+        assert!(!attempts.attempt(None, sender));
+        thread::sleep(Duration::from_millis(150));
+        assert!(attempts.attempt(None, sender));
+        assert!(!attempts.attempt(None, sender));
+
+        // Once the window is up, the failures are forgotten altogether:
+        thread::sleep(Duration::from_millis(150));
+        attempts.reap();
+        assert!(attempts.failures.lock().unwrap().is_empty());
+        assert!(attempts.share_failures.lock().unwrap().is_empty());
+    }
+}
@@ -1,12 +1,37 @@
 use structopt::StructOpt;
-use std::path::PathBuf;
+use serde_derive::{Serialize,Deserialize};
+use std::path::{Path,PathBuf};
+use std::env;
+use std::fs;
+use serde::de::{self, Visitor};
+use crate::Err;
 
-#[derive(StructOpt, Debug)]
+/// Environment variables that set options start with this, followed by the option's
+/// name in upper case with underscores, like `FILE_STREAMER_ACK_TIMEOUT`:
+const ENV_PREFIX: &str = "FILE_STREAMER_";
+
+#[derive(StructOpt, Serialize, Deserialize, Debug, Clone)]
 #[structopt(
     about = "A File Streamer"
 )]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Options {
 
+    #[structopt(
+        long = "config",
+        help = "read options from this TOML file, using the same names as the flags. Flags take precedence over FILE_STREAMER_* environment variables, which take precedence over the file",
+        parse(from_os_str)
+    )]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    #[structopt(
+        long = "print-config",
+        help = "print the options that would be used, as TOML, and exit"
+    )]
+    #[serde(skip)]
+    pub print_config: bool,
+
     #[structopt(
         short = "a",
         long = "address",
@@ -15,11 +40,409 @@ pub struct Options {
     )]
     pub address: std::net::SocketAddr,
 
+    #[structopt(
+        long = "tls-cert",
+        help = "serve HTTPS using this PEM encoded certificate chain (needs --tls-key too)",
+        requires = "tls_key",
+        conflicts_with = "tls_self_signed",
+        parse(from_os_str)
+    )]
+    pub tls_cert: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-key",
+        help = "the PEM encoded private key for the certificate given with --tls-cert",
+        requires = "tls_cert",
+        parse(from_os_str)
+    )]
+    pub tls_key: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-self-signed",
+        help = "serve HTTPS using a self signed certificate for this machine, made the first time it's needed"
+    )]
+    pub tls_self_signed: bool,
+
+    #[structopt(
+        long = "tls-self-signed-dir",
+        default_value = "file_streamer_tls",
+        help = "where to keep the self signed certificate and its key",
+        parse(from_os_str)
+    )]
+    pub tls_self_signed_dir: PathBuf,
+
+    #[structopt(
+        long = "https-redirect",
+        help = "also listen for plain HTTP on this address, redirecting everything to HTTPS (needs --tls-cert or --tls-self-signed)"
+    )]
+    pub https_redirect: Option<std::net::SocketAddr>,
+
     #[structopt(
         long = "client-files",
         help = "serve these files instead of the embedded client files",
         parse(from_os_str)
     )]
-    pub client_files: Option<PathBuf>
+    pub client_files: Option<PathBuf>,
+
+    #[structopt(
+        long = "compress",
+        help = "compress downloads on the fly for receivers that support it"
+    )]
+    pub compress: bool,
+
+    #[structopt(
+        long = "multicast",
+        help = "let simultaneous downloads of the same file share one upload from the sender"
+    )]
+    pub multicast: bool,
+
+    #[structopt(
+        long = "multicast-buffer",
+        default_value = "16",
+        help = "how many chunks to buffer for each receiver of a shared upload"
+    )]
+    pub multicast_buffer: usize,
+
+    #[structopt(
+        long = "multicast-slow-timeout",
+        default_value = "30",
+        help = "seconds to wait for a receiver of a shared upload to catch up before dropping it"
+    )]
+    pub multicast_slow_timeout: u64,
+
+    #[structopt(
+        long = "stream-pending-ttl",
+        default_value = "300",
+        help = "seconds a download can wait for its sender to start uploading before it's dropped"
+    )]
+    pub stream_pending_ttl: u64,
+
+    #[structopt(
+        long = "stream-finished-ttl",
+        default_value = "60",
+        help = "seconds to remember a finished download for before it's cleaned up"
+    )]
+    pub stream_finished_ttl: u64,
+
+    #[structopt(
+        long = "stream-idle-ttl",
+        default_value = "120",
+        help = "seconds a download can wait on its sender for more bytes before it's given up on; time spent held back by bandwidth limits or a slow receiver doesn't count"
+    )]
+    pub stream_idle_ttl: u64,
+
+    #[structopt(
+        long = "reap-interval",
+        default_value = "10",
+        help = "seconds between each clean up of finished and abandoned downloads"
+    )]
+    pub reap_interval: u64,
+
+    #[structopt(
+        long = "ack-timeout",
+        default_value = "30",
+        help = "seconds to wait for a sender to acknowledge a download before giving up with a 504"
+    )]
+    pub ack_timeout: u64,
+
+    #[structopt(
+        long = "upload-timeout",
+        default_value = "30",
+        help = "seconds to wait for a sender to start uploading a file before giving up with a 504"
+    )]
+    pub upload_timeout: u64,
+
+    #[structopt(
+        long = "segment-timeout",
+        default_value = "60",
+        help = "seconds to wait for the next segment of an upload sent in segments before giving up on it"
+    )]
+    pub segment_timeout: u64,
+
+    #[structopt(
+        long = "token-ttl",
+        default_value = "3600",
+        help = "seconds that download links for a password protected share are valid for"
+    )]
+    pub token_ttl: u64,
+
+    #[structopt(
+        long = "password-attempts",
+        default_value = "5",
+        help = "how many wrong passwords an address can give for a share before it has to wait"
+    )]
+    pub password_attempts: u32,
+
+    #[structopt(
+        long = "password-share-attempts",
+        default_value = "100",
+        help = "how many wrong passwords a share can be given, from every address together, before everybody has to wait"
+    )]
+    pub password_share_attempts: u32,
+
+    #[structopt(
+        long = "password-window",
+        default_value = "60",
+        help = "seconds over which wrong passwords are counted"
+    )]
+    pub password_window: u64,
+
+    #[structopt(
+        long = "sender-grace",
+        default_value = "30",
+        help = "seconds to keep a disconnected sender's share around, waiting for it to reconnect"
+    )]
+    pub sender_grace: u64,
+
+    #[structopt(
+        long = "http-rate",
+        default_value = "20",
+        help = "HTTP requests per second allowed from each address, on average (0 for no limit)"
+    )]
+    pub http_rate: f64,
+
+    #[structopt(
+        long = "http-burst",
+        default_value = "100",
+        help = "HTTP requests that each address can make at once before the rate limit kicks in"
+    )]
+    pub http_burst: u32,
+
+    #[structopt(
+        long = "ws-message-rate",
+        default_value = "5",
+        help = "messages per second allowed on each receiver websocket, on average (0 for no limit)"
+    )]
+    pub ws_message_rate: f64,
+
+    #[structopt(
+        long = "ws-message-burst",
+        default_value = "20",
+        help = "messages that each receiver websocket can send at once before the rate limit kicks in"
+    )]
+    pub ws_message_burst: u32,
+
+    #[structopt(
+        long = "download-rate",
+        default_value = "1",
+        help = "downloads per second allowed for each receiver, on average (0 for no limit); requests for later parts of a file that the receiver is already downloading don't count"
+    )]
+    pub download_rate: f64,
+
+    #[structopt(
+        long = "download-burst",
+        default_value = "10",
+        help = "downloads that each receiver can start at once before the rate limit kicks in"
+    )]
+    pub download_burst: u32,
+
+    #[structopt(
+        long = "bandwidth",
+        default_value = "0",
+        help = "bytes per second that can be relayed across all transfers (0 for no limit)"
+    )]
+    pub bandwidth: u64,
+
+    #[structopt(
+        long = "sender-bandwidth",
+        default_value = "0",
+        help = "bytes per second that can be relayed from each sender (0 for no limit)"
+    )]
+    pub sender_bandwidth: u64,
+
+    #[structopt(
+        long = "stream-bandwidth",
+        default_value = "0",
+        help = "bytes per second that can be relayed in each transfer (0 for no limit)"
+    )]
+    pub stream_bandwidth: u64
+
+}
+
+impl Options {
+    /// Work out our options. Each one comes from the first of these that sets it:
+    ///
+    /// 1. A command line flag.
+    /// 2. A `FILE_STREAMER_*` environment variable.
+    /// 3. The `--config` file (or the file named by `FILE_STREAMER_CONFIG`).
+    /// 4. The default value.
+    pub fn load() -> Result<Options, Err> {
+        let matches = Options::clap().get_matches();
+        let cli = Options::from_clap(&matches);
+
+        // Everything from the command line, including defaults for anything not given:
+        let mut values = match toml::Value::try_from(&cli) {
+            Ok(toml::Value::Table(values)) => values,
+            _ => return Err(Err::new("Can't encode options"))
+        };
+
+        let config_path = cli.config.clone().or_else(|| env::var_os(format!("{}CONFIG", ENV_PREFIX)).map(PathBuf::from));
+        let config = match &config_path {
+            Some(path) => read_config(path)?,
+            None => toml::value::Table::new()
+        };
+
+        // Config files can't contain anything that isn't an option, but other programs might
+        // use environment variables that look like ours, so we just warn about those:
+        let known = option_names();
+        let env_names: Vec<String> = env_names().into_iter()
+            .filter(|name| {
+                let is_option = known.contains(&name.as_str());
+                if !is_option {
+                    eprintln!("Ignoring {}, which doesn't set an option", env_name(name));
+                }
+                is_option
+            })
+            .collect();
+
+        let names: Vec<String> = values.keys()
+            .chain(config.keys())
+            .cloned()
+            .chain(env_names)
+            .collect();
+        for name in names {
+            // Flags that were actually given win over everything else:
+            if matches.occurrences_of(name.replace('-', "_")) > 0 { continue }
+            if let Some(value) = env::var(env_name(&name)).ok().map(|v| env_value(&v)) {
+                values.insert(name, value);
+            } else if let Some(value) = config.get(&name) {
+                values.insert(name, value.clone());
+            }
+        }
+
+        let mut options: Options = toml::Value::Table(values).try_into()
+            .map_err(|e| Err::new(format!("Invalid options: {}", e)))?;
+        options.config = config_path;
+        options.print_config = cli.print_config;
+        options.check()?;
+        Ok(options)
+    }
+    // The command line parser checks which flags go together, but options can come from
+    // anywhere, so we check again once we have all of them:
+    fn check(&self) -> Result<(), Err> {
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(Err::new("tls-cert and tls-key need to be given together"))
+        }
+        if self.tls_self_signed && self.tls_cert.is_some() {
+            return Err(Err::new("tls-self-signed can't be used with tls-cert"))
+        }
+        if self.https_redirect.is_some() && self.tls_cert.is_none() && !self.tls_self_signed {
+            return Err(Err::new("https-redirect needs HTTPS, from tls-cert or tls-self-signed"))
+        }
+        Ok(())
+    }
+    /// The options as they'd be written in a config file:
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("options can be encoded as TOML")
+    }
+}
+
+fn read_config(path: &Path) -> Result<toml::value::Table, Err> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| Err::new(format!("Can't read config file {}: {}", path.display(), e)))?;
+    contents.parse()
+        .map_err(|e| Err::new(format!("Invalid config file {}: {}", path.display(), e)))
+}
+
+fn env_name(name: &str) -> String {
+    format!("{}{}", ENV_PREFIX, name.replace('-', "_").to_uppercase())
+}
+
+// The option names of any FILE_STREAMER_* environment variables, so that we can
+// set options from them that aren't set anywhere else:
+fn env_names() -> Vec<String> {
+    env::vars()
+        .filter_map(|(key, _)| {
+            let name = key.strip_prefix(ENV_PREFIX)?;
+            if name == "CONFIG" { return None }
+            Some(name.to_lowercase().replace('_', "-"))
+        })
+        .collect()
+}
+
+// The names of the options that can be set, as they appear in config files. Serde knows
+// them, and hands them to whatever it's deserializing a struct from:
+fn option_names() -> &'static [&'static str] {
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de, 'a> de::Deserializer<'de> for FieldNames<'a> {
+        type Error = de::value::Error;
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("only structs have field names"))
+        }
+        fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], _visitor: V) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("just looking at the field names"))
+        }
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut names: &'static [&'static str] = &[];
+    let _ = <Options as serde::Deserialize>::deserialize(FieldNames(&mut names));
+    names
+}
+
+// Environment variables are just strings, so treat them as TOML values if they look like
+// one, for numbers and booleans, and as strings otherwise, for addresses and paths:
+fn env_value(value: &str) -> toml::Value {
+    format!("value = {}", value).parse::<toml::Value>().ok()
+        .and_then(|v| v.get("value").cloned())
+        .unwrap_or_else(|| toml::Value::String(value.to_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn defaults() -> Options {
+        Options::from_iter(&["file_streamer"])
+    }
+
+    #[test]
+    fn knows_option_names() {
+        let names = option_names();
+        assert!(names.contains(&"address"));
+        assert!(names.contains(&"tls-cert"));
+        assert!(names.contains(&"https-redirect"));
+        assert!(!names.contains(&"config"));
+        assert!(!names.contains(&"print-config"));
+    }
+
+    #[test]
+    fn checks_options_that_go_together() {
+        assert!(defaults().check().is_ok());
+
+        let mut cert_only = defaults();
+        cert_only.tls_cert = Some("cert.pem".into());
+        assert!(cert_only.check().is_err());
+
+        let mut key_only = defaults();
+        key_only.tls_key = Some("key.pem".into());
+        assert!(key_only.check().is_err());
+
+        let mut both = defaults();
+        both.tls_cert = Some("cert.pem".into());
+        both.tls_key = Some("key.pem".into());
+        assert!(both.check().is_ok());
+        both.tls_self_signed = true;
+        assert!(both.check().is_err());
+
+        let mut redirect = defaults();
+        redirect.https_redirect = Some("0.0.0.0:80".parse().unwrap());
+        assert!(redirect.check().is_err());
+        redirect.tls_self_signed = true;
+        assert!(redirect.check().is_ok());
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn reads_env_values() {
+        assert_eq!(env_value("60"), toml::Value::Integer(60));
+        assert_eq!(env_value("true"), toml::Value::Boolean(true));
+        assert_eq!(env_value("0.0.0.0:80"), toml::Value::String("0.0.0.0:80".to_owned()));
+        assert_eq!(env_name("tls-self-signed"), "FILE_STREAMER_TLS_SELF_SIGNED");
+    }
+}
@@ -0,0 +1,295 @@
+use std::io::Write;
+use futures::{Async, Poll, Stream, try_ready};
+use flate2::write::GzEncoder;
+use crate::Err;
+
+/// The ways in which we can compress a stream of bytes:
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+    Zstd
+}
+
+impl Encoding {
+    /// The name of the encoding in `Accept-Encoding` and `Content-Encoding` headers:
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd"
+        }
+    }
+}
+
+// Which encodings we'd rather use, if the client likes them equally.
+// Files are compressed on the fly, so speed matters more than size here:
+const PREFERRED: [Encoding; 3] = [Encoding::Zstd, Encoding::Brotli, Encoding::Gzip];
+
+/// Pick an encoding given the value of an `Accept-Encoding` header, or None if the
+/// client doesn't accept any encoding that we support.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut wildcard = None;
+    let mut accepted: Vec<(&str, f32)> = Vec::new();
+
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let q = parts
+            .filter_map(|p| {
+                let p = p.trim();
+                if p.starts_with("q=") { p[2..].trim().parse::<f32>().ok() } else { None }
+            })
+            .next()
+            .unwrap_or(1.0);
+        if name == "*" {
+            wildcard = Some(q);
+        } else if !name.is_empty() {
+            accepted.push((name, q));
+        }
+    }
+
+    let quality = |encoding: Encoding| {
+        accepted.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(encoding.name()))
+            .map(|&(_, q)| q)
+            .or(wildcard)
+            .unwrap_or(0.0)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in PREFERRED.iter() {
+        let q = quality(encoding);
+        if q > 0.0 && best.map(|(_, best_q)| q > best_q).unwrap_or(true) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Is a file with the given content type worth compressing? Media, archives and
+/// the like are already compressed, so we'd just be burning CPU on them.
+pub fn worth_compressing(content_type: &str) -> bool {
+    let content_type = content_type.to_ascii_lowercase();
+    let mut parts = content_type.splitn(2, '/');
+    let top = parts.next().unwrap_or("");
+    let sub = parts.next().unwrap_or("").split(';').next().unwrap_or("").trim();
+
+    match top {
+        "image" => sub == "svg+xml" || sub == "bmp" || sub == "x-icon",
+        "audio" | "video" => false,
+        "font" => sub == "ttf" || sub == "otf",
+        "application" => match sub {
+            "zip" | "gzip" | "x-gzip" | "x-bzip" | "x-bzip2" | "x-xz" | "x-lzma" | "zstd"
+            | "x-7z-compressed" | "x-rar-compressed" | "vnd.rar" | "x-compress"
+            | "x-compressed" | "java-archive" | "epub+zip" | "pdf" | "x-apple-diskimage"
+            | "vnd.android.package-archive" | "font-woff" | "x-font-woff" => false,
+            _ if sub.starts_with("vnd.openxmlformats") => false,
+            _ => true
+        },
+        _ => true
+    }
+}
+
+/// Something that compresses bytes written into it, and lets us take
+/// whatever compressed output is ready as we go.
+trait Encoder: Send {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()>;
+    fn take_output(&mut self) -> Vec<u8>;
+    fn finish(self: Box<Self>) -> std::io::Result<Vec<u8>>;
+}
+
+impl Encoder for GzEncoder<Vec<u8>> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.write_all(data)
+    }
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::replace(self.get_mut(), Vec::new())
+    }
+    fn finish(self: Box<Self>) -> std::io::Result<Vec<u8>> {
+        GzEncoder::finish(*self)
+    }
+}
+
+impl Encoder for brotli::CompressorWriter<Vec<u8>> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.write_all(data)
+    }
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::replace(self.get_mut(), Vec::new())
+    }
+    fn finish(self: Box<Self>) -> std::io::Result<Vec<u8>> {
+        // Brotli finishes the stream off when we take the inner writer back:
+        Ok(self.into_inner())
+    }
+}
+
+impl Encoder for zstd::stream::write::Encoder<'static, Vec<u8>> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.write_all(data)
+    }
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::replace(self.get_mut(), Vec::new())
+    }
+    fn finish(self: Box<Self>) -> std::io::Result<Vec<u8>> {
+        zstd::stream::write::Encoder::finish(*self)
+    }
+}
+
+/// Compress a stream of bytes as it flows through.
+pub fn compress<S>(encoding: Encoding, inner: S) -> Compressed<S> {
+    let encoder: Result<Box<dyn Encoder>, _> = match encoding {
+        Encoding::Gzip => {
+            Ok(Box::new(GzEncoder::new(Vec::new(), flate2::Compression::default())) as Box<dyn Encoder>)
+        },
+        Encoding::Brotli => {
+            // A low quality setting keeps brotli fast enough to use on the fly:
+            Ok(Box::new(brotli::CompressorWriter::new(Vec::new(), 4096, 4, 22)) as Box<dyn Encoder>)
+        },
+        Encoding::Zstd => {
+            zstd::stream::write::Encoder::new(Vec::new(), 3).map(|e| Box::new(e) as Box<dyn Encoder>)
+        }
+    };
+    Compressed { inner, encoder: encoder.map(Some).map_err(compress_error) }
+}
+
+pub struct Compressed<S> {
+    inner: S,
+    // If the encoder couldn't be created, we hand the error back on the first poll:
+    encoder: Result<Option<Box<dyn Encoder>>, Err>
+}
+
+impl <S> Stream for Compressed<S>
+    where S: Stream<Item = Vec<u8>, Error = Err>
+{
+    type Item = Vec<u8>;
+    type Error = Err;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, Err> {
+        loop {
+            let encoder = match &mut self.encoder {
+                Ok(Some(encoder)) => encoder,
+                Ok(None) => return Ok(Async::Ready(None)),
+                Err(e) => return Err(e.clone())
+            };
+            match try_ready!(self.inner.poll()) {
+                // Compress the next chunk, and hand back any output. The encoder
+                // buffers internally, so if there's no output yet, keep reading:
+                Some(chunk) => {
+                    encoder.write(&chunk).map_err(compress_error)?;
+                    let out = encoder.take_output();
+                    if !out.is_empty() {
+                        return Ok(Async::Ready(Some(out)));
+                    }
+                },
+                // No more input, so flush out whatever is left:
+                None => {
+                    let encoder = match std::mem::replace(&mut self.encoder, Ok(None)) {
+                        Ok(Some(encoder)) => encoder,
+                        _ => return Ok(Async::Ready(None))
+                    };
+                    let out = encoder.finish().map_err(compress_error)?;
+                    return Ok(Async::Ready(Some(out)));
+                }
+            }
+        }
+    }
+}
+
+fn compress_error(e: std::io::Error) -> Err {
+    Err::new(format!("Compression error: {}", e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use futures::{stream, Future};
+
+    #[test]
+    fn negotiates_by_quality() {
+        assert_eq!(negotiate("gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("GZip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0.8, gzip; q=0.9"), Some(Encoding::Gzip));
+        // Ties go to the encoding we prefer:
+        assert_eq!(negotiate("gzip, br, zstd"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("gzip, br"), Some(Encoding::Brotli));
+    }
+
+    #[test]
+    fn negotiates_wildcards() {
+        assert_eq!(negotiate("*"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("*;q=0.5, gzip"), Some(Encoding::Gzip));
+        // Anything named is excluded from the wildcard:
+        assert_eq!(negotiate("*, zstd;q=0"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip, *;q=0"), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn negotiates_nothing() {
+        assert_eq!(negotiate(""), None);
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate("identity, *;q=0"), None);
+        assert_eq!(negotiate("deflate, compress"), None);
+        assert_eq!(negotiate("gzip;q=0"), None);
+        assert_eq!(negotiate("gzip;q=0, br;q=0, zstd;q=0"), None);
+    }
+
+    #[test]
+    fn knows_what_is_worth_compressing() {
+        for content_type in &["text/plain", "text/html; charset=utf-8", "application/json",
+                              "image/svg+xml", "font/ttf", "application/octet-stream"] {
+            assert!(worth_compressing(content_type), "{}", content_type);
+        }
+        for content_type in &["image/png", "video/mp4", "audio/mpeg", "application/zip",
+                              "APPLICATION/PDF", "font/woff2", "application/x-7z-compressed",
+                              "application/vnd.openxmlformats-officedocument.wordprocessingml.document"] {
+            assert!(!worth_compressing(content_type), "{}", content_type);
+        }
+    }
+
+    fn round_trip(encoding: Encoding, chunks: Vec<Vec<u8>>, decode: impl FnOnce(&[u8]) -> Vec<u8>) {
+        let expected: Vec<u8> = chunks.concat();
+        let compressed = compress(encoding, stream::iter_ok::<_, Err>(chunks)).concat2().wait().unwrap();
+        assert_eq!(decode(&compressed), expected, "{:?}", encoding);
+    }
+
+    fn text() -> Vec<Vec<u8>> {
+        (0..100).map(|n| format!("line {} of some fairly repetitive text\n", n).into_bytes()).collect()
+    }
+
+    fn gunzip(bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        flate2::read::GzDecoder::new(bytes).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    fn unbrotli(bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        brotli::Decompressor::new(bytes, 4096).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    fn unzstd(bytes: &[u8]) -> Vec<u8> {
+        zstd::stream::decode_all(bytes).unwrap()
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        round_trip(Encoding::Gzip, text(), gunzip);
+        round_trip(Encoding::Gzip, Vec::new(), gunzip);
+    }
+
+    #[test]
+    fn brotli_round_trip() {
+        round_trip(Encoding::Brotli, text(), unbrotli);
+        round_trip(Encoding::Brotli, Vec::new(), unbrotli);
+    }
+
+    #[test]
+    fn zstd_round_trip() {
+        round_trip(Encoding::Zstd, text(), unzstd);
+        round_trip(Encoding::Zstd, Vec::new(), unzstd);
+    }
+}
@@ -0,0 +1,46 @@
+use sha2::{Digest, Sha256};
+
+/// A SHA-256 digest of a file, which senders can declare as base64 in the
+/// `digest` field of `FileInfoForStream`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sha256Digest([u8; 32]);
+
+impl Sha256Digest {
+    pub fn parse(s: &str) -> Option<Sha256Digest> {
+        let bytes = base64::decode(s.trim()).ok()?;
+        if bytes.len() != 32 { return None }
+        let mut d = [0; 32];
+        d.copy_from_slice(&bytes);
+        Some(Sha256Digest(d))
+    }
+    pub fn to_base64(&self) -> String {
+        base64::encode(&self.0)
+    }
+    /// The value of an RFC 9530 `Repr-Digest` header:
+    pub fn repr_digest_header(&self) -> String {
+        format!("sha-256=:{}:", self.to_base64())
+    }
+    /// The value of an RFC 3230 `Digest` header, for older clients:
+    pub fn digest_header(&self) -> String {
+        format!("SHA-256={}", self.to_base64())
+    }
+}
+
+/// Hashes the bytes of a file as they pass through, so that we can check
+/// them against the digest that the sender declared once they're all in.
+pub struct Verifier {
+    hasher: Sha256,
+    expected: Sha256Digest
+}
+
+impl Verifier {
+    pub fn new(expected: Sha256Digest) -> Verifier {
+        Verifier { hasher: Sha256::new(), expected }
+    }
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+    }
+    pub fn matches(self) -> bool {
+        self.hasher.finalize().as_slice() == &self.expected.0[..]
+    }
+}
@@ -0,0 +1,464 @@
+//! The format used for end-to-end encrypted transfers. The server never sees
+//! the key and never looks inside encrypted files; it just relays bytes. This
+//! is a reference implementation for clients, built as part of the `file_streamer`
+//! library so that they can use it, and mirrors the browser one in
+//! `client/src/app/services/e2e.ts`.
+//!
+//! **Key**: 32 random bytes, encoded as unpadded base64url and kept in the share
+//! URL fragment (`#key=...`), which browsers never send to the server.
+//!
+//! **File names**: `base64url(nonce || ciphertext)`, where `nonce` is 12 random bytes
+//! and the ciphertext (with its 16 byte tag) is the AES-256-GCM encryption of the
+//! UTF-8 name, with the additional data `"name"`.
+//!
+//! **File contents**: a 16 byte header followed by a series of chunks. The header is:
+//!
+//! ```text
+//! "SE2E" (4 bytes) | version: 1 (1 byte) | chunk size (4 bytes, big endian) | nonce prefix (7 random bytes)
+//! ```
+//!
+//! Each chunk is up to `chunk size` bytes of the file, encrypted with AES-256-GCM and
+//! with the header as additional data, so that it's 16 bytes bigger once encrypted.
+//! The nonce for a chunk is:
+//!
+//! ```text
+//! nonce prefix (7 bytes) | chunk number, from 0 (4 bytes, big endian) | 1 if last chunk else 0 (1 byte)
+//! ```
+//!
+//! Every chunk but the last holds exactly `chunk size` bytes. The last chunk holds
+//! whatever is left, which is nothing at all for an empty file. Marking the last chunk
+//! in its nonce means that a truncated file fails to decrypt rather than looking complete.
+
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use aes_gcm::aead::{Aead, Payload};
+use derive_more::Display;
+use rand::Rng;
+
+pub const KEY_LEN: usize = 32;
+pub const HEADER_LEN: usize = 16;
+pub const TAG_LEN: usize = 16;
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+const MAGIC: &[u8; 4] = b"SE2E";
+const VERSION: u8 = 1;
+const NAME_NONCE_LEN: usize = 12;
+const NAME_AAD: &[u8] = b"name";
+
+/// Why something couldn't be encrypted or decrypted.
+#[derive(Display, Debug, Clone)]
+pub struct Err {
+    msg: String
+}
+
+impl Err {
+    fn new(s: impl Into<String>) -> Err {
+        Err { msg: s.into() }
+    }
+}
+
+impl std::error::Error for Err {}
+
+/// The key that a share is encrypted with.
+#[derive(Clone)]
+pub struct Key([u8; KEY_LEN]);
+
+impl Key {
+    pub fn generate() -> Key {
+        let mut key = [0; KEY_LEN];
+        rand::thread_rng().fill(&mut key);
+        Key(key)
+    }
+    /// Read the key from a URL fragment like "key=...", or just the encoded key itself.
+    pub fn from_fragment(fragment: &str) -> Option<Key> {
+        let encoded = fragment.trim_start_matches('#')
+            .split('&')
+            .find_map(|part| part.strip_prefix("key="))
+            .unwrap_or(fragment);
+        let bytes = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).ok()?;
+        if bytes.len() != KEY_LEN { return None }
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(&bytes);
+        Some(Key(key))
+    }
+    /// The URL fragment (without the '#') to share the key in.
+    pub fn to_fragment(&self) -> String {
+        format!("key={}", base64::encode_config(&self.0, base64::URL_SAFE_NO_PAD))
+    }
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new_from_slice(&self.0).expect("key is the right length")
+    }
+}
+
+/// How big a file of `size` bytes will be once encrypted. Senders declare this
+/// size, since it's the number of bytes that they'll actually upload.
+pub fn encrypted_size(size: u64, chunk_size: u32) -> u64 {
+    let chunk_size = chunk_size as u64;
+    let chunks = std::cmp::max(1, (size + chunk_size - 1) / chunk_size);
+    HEADER_LEN as u64 + size + chunks * TAG_LEN as u64
+}
+
+pub fn encrypt_name(key: &Key, name: &str) -> String {
+    let mut nonce = [0; NAME_NONCE_LEN];
+    rand::thread_rng().fill(&mut nonce);
+    let ciphertext = key.cipher()
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: name.as_bytes(), aad: NAME_AAD })
+        .expect("encryption can't fail");
+    let mut out = nonce.to_vec();
+    out.extend_from_slice(&ciphertext);
+    base64::encode_config(&out, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn decrypt_name(key: &Key, encrypted: &str) -> Result<String, Err> {
+    let bytes = base64::decode_config(encrypted, base64::URL_SAFE_NO_PAD)
+        .map_err(|e| Err::new(format!("Encrypted name isn't valid base64: {}", e)))?;
+    if bytes.len() < NAME_NONCE_LEN + TAG_LEN {
+        return Err(Err::new("Encrypted name is too short"))
+    }
+    let (nonce, ciphertext) = bytes.split_at(NAME_NONCE_LEN);
+    let name = key.cipher()
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: NAME_AAD })
+        .map_err(|_| Err::new("Can't decrypt name; wrong key?"))?;
+    String::from_utf8(name).map_err(|_| Err::new("Decrypted name isn't valid UTF-8"))
+}
+
+/// Encrypt a file as it's read. Push bytes in as they come, and call `finish`
+/// at the end. Each call hands back the encrypted bytes that are ready.
+pub struct Encryptor {
+    cipher: Aes256Gcm,
+    header: [u8; HEADER_LEN],
+    header_sent: bool,
+    chunk_size: usize,
+    counter: u32,
+    buffer: Vec<u8>
+}
+
+impl Encryptor {
+    pub fn new(key: &Key, chunk_size: u32) -> Encryptor {
+        let mut nonce_prefix = [0; 7];
+        rand::thread_rng().fill(&mut nonce_prefix);
+        Encryptor::with_nonce_prefix(key, chunk_size, nonce_prefix)
+    }
+
+    // The nonce prefix must never be reused with the same key; it's only chosen
+    // by hand to check the output against known bytes:
+    fn with_nonce_prefix(key: &Key, chunk_size: u32, nonce_prefix: [u8; 7]) -> Encryptor {
+        assert!(chunk_size > 0, "chunk size must be greater than 0");
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(MAGIC);
+        header[4] = VERSION;
+        header[5..9].copy_from_slice(&chunk_size.to_be_bytes());
+        header[9..].copy_from_slice(&nonce_prefix);
+        Encryptor {
+            cipher: key.cipher(),
+            header,
+            header_sent: false,
+            chunk_size: chunk_size as usize,
+            counter: 0,
+            buffer: Vec::new()
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<u8>, Err> {
+        self.buffer.extend_from_slice(data);
+        let mut out = self.take_header();
+        // A full chunk is only sent once we know that it's not the last one:
+        while self.buffer.len() > self.chunk_size {
+            let rest = self.buffer.split_off(self.chunk_size);
+            let chunk = std::mem::replace(&mut self.buffer, rest);
+            out.extend(self.seal(&chunk, false)?);
+        }
+        Ok(out)
+    }
+
+    pub fn finish(mut self) -> Result<Vec<u8>, Err> {
+        let mut out = self.take_header();
+        let chunk = std::mem::replace(&mut self.buffer, Vec::new());
+        out.extend(self.seal(&chunk, true)?);
+        Ok(out)
+    }
+
+    fn take_header(&mut self) -> Vec<u8> {
+        if self.header_sent { return Vec::new() }
+        self.header_sent = true;
+        self.header.to_vec()
+    }
+
+    fn seal(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, Err> {
+        let nonce = chunk_nonce(&self.header, self.counter, last);
+        self.counter = self.counter.checked_add(1).ok_or_else(|| Err::new("File has too many chunks"))?;
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: chunk, aad: &self.header })
+            .map_err(|_| Err::new("Failed to encrypt chunk"))
+    }
+}
+
+/// Decrypt a file as it arrives. Push bytes in as they come, and call `finish`
+/// at the end, which fails if the file was cut short. Each call hands back the
+/// decrypted bytes that are ready.
+pub struct Decryptor {
+    cipher: Aes256Gcm,
+    header: Option<[u8; HEADER_LEN]>,
+    chunk_size: usize,
+    counter: u32,
+    buffer: Vec<u8>
+}
+
+impl Decryptor {
+    pub fn new(key: &Key) -> Decryptor {
+        Decryptor {
+            cipher: key.cipher(),
+            header: None,
+            chunk_size: 0,
+            counter: 0,
+            buffer: Vec::new()
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<u8>, Err> {
+        self.buffer.extend_from_slice(data);
+        if self.header.is_none() {
+            if self.buffer.len() < HEADER_LEN { return Ok(Vec::new()) }
+            let rest = self.buffer.split_off(HEADER_LEN);
+            let header = std::mem::replace(&mut self.buffer, rest);
+            self.read_header(&header)?;
+        }
+        // Like when encrypting, a full chunk is only the last one if nothing follows it:
+        let sealed_size = self.chunk_size + TAG_LEN;
+        let mut out = Vec::new();
+        while self.buffer.len() > sealed_size {
+            let rest = self.buffer.split_off(sealed_size);
+            let chunk = std::mem::replace(&mut self.buffer, rest);
+            out.extend(self.open(&chunk, false)?);
+        }
+        Ok(out)
+    }
+
+    pub fn finish(mut self) -> Result<Vec<u8>, Err> {
+        if self.header.is_none() || self.buffer.len() < TAG_LEN {
+            return Err(Err::new("Encrypted file is truncated"))
+        }
+        let chunk = std::mem::replace(&mut self.buffer, Vec::new());
+        self.open(&chunk, true)
+    }
+
+    fn read_header(&mut self, header: &[u8]) -> Result<(), Err> {
+        if &header[..4] != MAGIC {
+            return Err(Err::new("Not an encrypted file"))
+        }
+        if header[4] != VERSION {
+            return Err(Err::new(format!("Unsupported encrypted file version {}", header[4])))
+        }
+        let mut chunk_size = [0; 4];
+        chunk_size.copy_from_slice(&header[5..9]);
+        let chunk_size = u32::from_be_bytes(chunk_size);
+        if chunk_size == 0 {
+            return Err(Err::new("Encrypted file has a chunk size of 0"))
+        }
+        let mut h = [0; HEADER_LEN];
+        h.copy_from_slice(header);
+        self.header = Some(h);
+        self.chunk_size = chunk_size as usize;
+        Ok(())
+    }
+
+    fn open(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, Err> {
+        let header = self.header.expect("header is read before any chunks");
+        let nonce = chunk_nonce(&header, self.counter, last);
+        self.counter = self.counter.checked_add(1).ok_or_else(|| Err::new("File has too many chunks"))?;
+        self.cipher
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: chunk, aad: &header })
+            .map_err(|_| Err::new("Can't decrypt chunk; the file is corrupt, truncated or the key is wrong"))
+    }
+}
+
+fn chunk_nonce(header: &[u8; HEADER_LEN], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[..7].copy_from_slice(&header[9..]);
+    nonce[7..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use aes_gcm::aead::AeadInPlace;
+
+    // Shared with the tests in client/src/app/services/e2e.test.ts, so that both
+    // sides are known to agree on the format:
+    const KEY_FRAGMENT: &str = "key=AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8";
+    const NONCE_PREFIX: [u8; 7] = [0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6];
+    const CHUNK_SIZE: u32 = 4;
+    const HELLO_WORLD: &str = "534532450100000004a0a1a2a3a4a5a6\
+        62b470c538444a878649886cd7275e51d16bcbfa\
+        3ec260a8211903e376f6980d9d640d25d1095b0b\
+        93c1e56618ca4836fa46a11958ffccfb769d25";
+    const HELLO_WO: &str = "534532450100000004a0a1a2a3a4a5a6\
+        62b470c538444a878649886cd7275e51d16bcbfa\
+        8626b786cabcf3057c4f51bc702623a98ea6f86a";
+    const EMPTY: &str = "534532450100000004a0a1a2a3a4a5a6\
+        fde53ba7b951af495281984e0e0b5f42";
+    const CAFE_NAME: &str = "EBESExQVFhcYGRobHp_-1eDnTsu-EVjXZhLoSyc76Ja38JnyCQ";
+
+    fn key() -> Key {
+        Key::from_fragment(KEY_FRAGMENT).unwrap()
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i+2], 16).unwrap()).collect()
+    }
+
+    fn encrypt(plaintext: &[u8], pieces: usize) -> Vec<u8> {
+        let mut e = Encryptor::with_nonce_prefix(&key(), CHUNK_SIZE, NONCE_PREFIX);
+        let mut out = Vec::new();
+        for piece in plaintext.chunks(pieces) {
+            out.extend(e.push(piece).unwrap());
+        }
+        out.extend(e.finish().unwrap());
+        out
+    }
+
+    fn decrypt(ciphertext: &[u8]) -> Result<Vec<u8>, Err> {
+        let mut d = Decryptor::new(&key());
+        let mut out = d.push(ciphertext)?;
+        out.extend(d.finish()?);
+        Ok(out)
+    }
+
+    #[test]
+    fn key_round_trips_through_fragment() {
+        assert_eq!(key().0.to_vec(), (0..32).collect::<Vec<u8>>());
+        assert_eq!(key().to_fragment(), KEY_FRAGMENT);
+        assert!(Key::from_fragment("key=AAEC").is_none());
+    }
+
+    #[test]
+    fn encrypts_to_known_bytes() {
+        for pieces in 1..12 {
+            assert_eq!(encrypt(b"hello world", pieces), hex(HELLO_WORLD));
+        }
+        assert_eq!(encrypt(b"hello wo", 3), hex(HELLO_WO));
+        assert_eq!(encrypt(b"", 1), hex(EMPTY));
+        assert_eq!(encrypted_size(11, CHUNK_SIZE), hex(HELLO_WORLD).len() as u64);
+        assert_eq!(encrypted_size(8, CHUNK_SIZE), hex(HELLO_WO).len() as u64);
+        assert_eq!(encrypted_size(0, CHUNK_SIZE), hex(EMPTY).len() as u64);
+    }
+
+    #[test]
+    fn tag_follows_each_chunk() {
+        let out = hex(HELLO_WORLD);
+        let header = &out[..HEADER_LEN];
+        let chunks = [(&b"hell"[..], false), (&b"o wo"[..], false), (&b"rld"[..], true)];
+        let mut offset = HEADER_LEN;
+        for (n, &(plain, last)) in chunks.iter().enumerate() {
+            let mut header_arr = [0; HEADER_LEN];
+            header_arr.copy_from_slice(header);
+            let nonce = chunk_nonce(&header_arr, n as u32, last);
+            let mut buf = plain.to_vec();
+            let tag = key().cipher()
+                .encrypt_in_place_detached(Nonce::from_slice(&nonce), header, &mut buf)
+                .unwrap();
+            assert_eq!(&out[offset..offset + plain.len()], &buf[..]);
+            assert_eq!(&out[offset + plain.len()..offset + plain.len() + TAG_LEN], &tag[..]);
+            offset += plain.len() + TAG_LEN;
+        }
+        assert_eq!(offset, out.len());
+    }
+
+    #[test]
+    fn decrypts_known_bytes() {
+        assert_eq!(decrypt(&hex(HELLO_WORLD)).unwrap(), b"hello world");
+        assert_eq!(decrypt(&hex(HELLO_WO)).unwrap(), b"hello wo");
+        assert_eq!(decrypt(&hex(EMPTY)).unwrap(), b"");
+
+        // Bytes can arrive in any size of piece:
+        let mut d = Decryptor::new(&key());
+        let mut out = Vec::new();
+        for byte in hex(HELLO_WORLD) {
+            out.extend(d.push(&[byte]).unwrap());
+        }
+        out.extend(d.finish().unwrap());
+        assert_eq!(out, b"hello world");
+    }
+
+    #[test]
+    fn last_chunk_is_marked() {
+        // A full chunk is the last one when nothing follows it:
+        // so "o wo" is sealed differently depending on whether "rld" comes after it:
+        let sealed = CHUNK_SIZE as usize + TAG_LEN;
+        let (short, long) = (hex(HELLO_WO), hex(HELLO_WORLD));
+        assert_eq!(short.len(), HEADER_LEN + 2 * sealed);
+        assert_eq!(short[..HEADER_LEN + sealed], long[..HEADER_LEN + sealed]);
+        assert_ne!(short[HEADER_LEN + sealed..], long[HEADER_LEN + sealed..HEADER_LEN + 2 * sealed]);
+
+        // An empty file is still one (empty) last chunk:
+        assert_eq!(hex(EMPTY).len(), HEADER_LEN + TAG_LEN);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let out = hex(HELLO_WORLD);
+        let sealed = CHUNK_SIZE as usize + TAG_LEN;
+
+        // Dropping whole chunks leaves a chunk that wasn't sealed as the last one:
+        assert!(decrypt(&out[..HEADER_LEN + 2 * sealed]).is_err());
+        assert!(decrypt(&out[..HEADER_LEN + sealed]).is_err());
+        // As does cutting a chunk short, or losing every chunk:
+        assert!(decrypt(&out[..out.len() - 1]).is_err());
+        assert!(decrypt(&out[..HEADER_LEN]).is_err());
+        assert!(decrypt(&out[..HEADER_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_reordered_chunks() {
+        let out = hex(HELLO_WORLD);
+        let sealed = CHUNK_SIZE as usize + TAG_LEN;
+        let (header, chunks) = out.split_at(HEADER_LEN);
+
+        let mut swapped = header.to_vec();
+        swapped.extend_from_slice(&chunks[sealed..2 * sealed]);
+        swapped.extend_from_slice(&chunks[..sealed]);
+        swapped.extend_from_slice(&chunks[2 * sealed..]);
+        assert!(decrypt(&swapped).is_err());
+
+        let mut last_first = header.to_vec();
+        last_first.extend_from_slice(&chunks[2 * sealed..]);
+        last_first.extend_from_slice(&chunks[..2 * sealed]);
+        assert!(decrypt(&last_first).is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_bytes() {
+        let mut out = hex(HELLO_WORLD);
+        out[HEADER_LEN] ^= 1;
+        assert!(decrypt(&out).is_err());
+
+        // The header is bound to every chunk, so it can't be swapped out either:
+        let mut out = hex(HELLO_WORLD);
+        out[5..9].copy_from_slice(&8u32.to_be_bytes());
+        assert!(decrypt(&out).is_err());
+
+        let mut out = hex(HELLO_WORLD);
+        out[9] ^= 1;
+        assert!(decrypt(&out).is_err());
+    }
+
+    #[test]
+    fn round_trips_with_random_nonces() {
+        let plaintext: Vec<u8> = (0..3 * DEFAULT_CHUNK_SIZE + 7).map(|n| n as u8).collect();
+        let mut e = Encryptor::new(&key(), DEFAULT_CHUNK_SIZE);
+        let mut out = e.push(&plaintext).unwrap();
+        out.extend(e.finish().unwrap());
+        assert_eq!(out.len() as u64, encrypted_size(plaintext.len() as u64, DEFAULT_CHUNK_SIZE));
+        assert_eq!(decrypt(&out).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn names_round_trip() {
+        assert_eq!(decrypt_name(&key(), CAFE_NAME).unwrap(), "café.txt");
+        let encrypted = encrypt_name(&key(), "café.txt");
+        assert_ne!(encrypted, CAFE_NAME);
+        assert_eq!(decrypt_name(&key(), &encrypted).unwrap(), "café.txt");
+        assert!(decrypt_name(&Key::generate(), CAFE_NAME).is_err());
+    }
+}
@@ -34,6 +34,16 @@ impl Id {
     pub fn none() -> Id {
         Id { val: [0; 16] }
     }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.val
+    }
+    // Ids are also sent as their 16 raw bytes at the start of binary messages:
+    pub fn from_bytes(bytes: &[u8]) -> Option<Id> {
+        if bytes.len() < 16 { return None }
+        let mut a = [0;16];
+        a.copy_from_slice(&bytes[..16]);
+        Some(Id { val: a })
+    }
 }
 
 // How to get an Id from a string:
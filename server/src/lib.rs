@@ -0,0 +1,6 @@
+//! The parts of the file streamer that other programs can use. The server itself
+//! never decrypts anything, but clients other than the browser one need to agree
+//! with it on the format of end-to-end encrypted transfers, so the reference
+//! implementation of that lives here.
+
+pub mod e2e;
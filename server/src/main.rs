@@ -5,18 +5,37 @@ mod id;
 mod state;
 mod messages;
 mod cli;
+mod range;
+mod archive;
+mod compress;
+mod metadata;
+mod multicast;
+mod digest;
+mod auth;
+mod ratelimit;
+mod throttle;
+mod tls;
 
 use serde_derive::{Serialize,Deserialize};
-use futures::{future, Future, Sink, Stream, sync::{oneshot,mpsc}};
+use futures::{future, stream, Async, Future, Poll, Sink, Stream, sync::{oneshot,mpsc}};
 use warp::{path, Filter, ws::{Message,WebSocket}};
-use warp::http::{Response,status::StatusCode};
-use std::sync::{Arc, RwLock};
+use warp::http::{Response,HeaderMap,status::StatusCode,header};
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use std::fmt;
+use std::net::SocketAddr;
+use tokio::timer::{Delay, Interval, Timeout};
 use derive_more::{FromStr,Display};
 use hyper::Body;
-use structopt::StructOpt;
 
-use crate::messages::{MsgToReceiver, MsgToSender};
+use crate::messages::{MsgToReceiver, MsgToSender, MsgFromSender, MsgFromReceiver, FileInfoForStream, NackReason, RejectReason, UnavailableReason};
 use crate::id::Id;
+use crate::range::ByteRange;
+use crate::archive::{Archive, ArchiveRequest, FileSelection, Kind};
+use crate::compress::Encoding;
+use crate::metadata::{Metadata, Disposition};
+use crate::digest::Sha256Digest;
 
 #[derive(FromStr)]
 struct FileId(Id);
@@ -27,14 +46,47 @@ struct SenderId(Id);
 #[derive(FromStr)]
 struct StreamId(Id);
 
+#[derive(Deserialize, Default)]
+struct DownloadQuery {
+    #[serde(default)]
+    disposition: Disposition,
+    #[serde(default)]
+    token: Option<String>
+}
+
+#[derive(Deserialize, Default)]
+struct ArchiveQuery {
+    #[serde(default)]
+    token: Option<String>
+}
+
+#[derive(Deserialize)]
+struct DropBoxQuery {
+    name: String
+}
+
 type State = Arc<state::State>;
 
+/// The bytes of a file being uploaded by a sender:
+pub type FileData = Box<dyn Stream<Item = Vec<u8>, Error = ()> + Send>;
+
 fn main() {
 
-    let opts = cli::Options::from_args();
+    let opts = match cli::Options::load() {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if opts.print_config {
+        print!("{}", opts.to_toml());
+        return
+    }
 
     // Make some shared state available in every route that needs it:
-    let state: State = Arc::new(state::State::new());
+    let state: State = Arc::new(state::State::new(opts.clone()));
+    let reaper_state = state.clone();
     let with_state = move || {
         let s = state.clone();
         warp::any().map(move || s.clone())
@@ -53,10 +105,11 @@ fn main() {
     // WS /api/receiver/ws
     let api_receiver_ws = path!("api" / "receiver" / "ws")
         .and(warp::ws2())
+        .and(warp::addr::remote())
         .and(with_state())
-        .map(|ws: warp::ws::Ws2, state: State| {
-            ws.on_upgrade(|websocket| {
-                handle_receiver_ws(websocket, state)
+        .map(|ws: warp::ws::Ws2, addr: Option<SocketAddr>, state: State| {
+            ws.on_upgrade(move |websocket| {
+                handle_receiver_ws(websocket, addr, state)
             })
         });
 
@@ -67,12 +120,55 @@ fn main() {
         .and(with_state())
         .and_then(handle_upload);
 
+    // upload part of a file to sender, starting at some offset. Segments
+    // can be retried, so a failed one doesn't fail the whole download:
+    let api_upload_segment = path!("api" / "upload" / StreamId / u64)
+        .and(warp::post2())
+        .and(warp::filters::body::stream())
+        .and(with_state())
+        .and_then(handle_upload_segment);
+
     // Download files from sender
     let api_download = path!("api" / "download" / SenderId / FileId)
         .and(warp::get2())
+        .and(query_or_default::<DownloadQuery>())
+        .and(warp::header::headers_cloned())
+        .and(warp::addr::remote())
         .and(with_state())
         .and_then(handle_download);
 
+    // Download an archive of several files from sender
+    let api_archive = path!("api" / "archive" / SenderId / ArchiveRequest)
+        .and(warp::get2())
+        .and(query_or_default::<ArchiveQuery>())
+        .and(warp::addr::remote())
+        .and(with_state())
+        .and_then(handle_archive);
+
+    // Offer a file to a sender's drop box
+    let api_drop_box = path!("api" / "dropbox" / SenderId)
+        .and(warp::post2())
+        .and(warp::query::<DropBoxQuery>())
+        .and(warp::header::headers_cloned())
+        .and(warp::filters::body::stream())
+        .and(with_state())
+        .and_then(handle_drop_box_upload);
+
+    // Download a file that was offered to the sender's drop box
+    let api_incoming = path!("api" / "incoming" / StreamId)
+        .and(warp::get2())
+        .and(with_state())
+        .and_then(handle_incoming_download);
+
+    // Turn away addresses that are making too many requests:
+    let rate_limit = warp::addr::remote()
+        .and(with_state())
+        .and_then(|addr: Option<SocketAddr>, state: State| {
+            if state.http_limits.allow(addr.map(|a| a.ip())) { Ok(()) }
+            else { Err(warp::reject::custom(ratelimit::RateLimited)) }
+        })
+        .untuple_one();
+
     // GET client files
     let client_files = opts.client_files;
     let other = warp::get2()
@@ -80,18 +176,86 @@ fn main() {
         .and_then(move |path| client::return_file(&client_files, path));
 
     // put our routes together and serve them:
-    let routes = api_sender_ws
-        .or(api_receiver_ws)
-        .or(api_upload)
-        .or(api_download)
-        .or(other);
+    let redirect_rate_limit = rate_limit.clone();
+    let routes = rate_limit.and(
+        api_sender_ws
+            .or(api_receiver_ws)
+            .or(api_upload_segment)
+            .or(api_upload)
+            .or(api_download)
+            .or(api_archive)
+            .or(api_drop_box)
+            .or(api_incoming)
+            .or(other)
+    ).recover(handle_rejection);
+
+    let address = opts.address;
+    let mut tls = opts.tls_cert.clone().and_then(|cert| opts.tls_key.clone().map(|key| (cert, key)));
+    if opts.tls_self_signed {
+        match tls::SelfSigned::load_or_generate(&opts.tls_self_signed_dir, address) {
+            Ok(cert) => {
+                println!("Starting server on {} (certificate SHA-256 fingerprint {})", address, cert.fingerprint);
+                tls = Some((cert.cert, cert.key));
+            },
+            Err(e) => {
+                eprintln!("Can't set up a self signed certificate: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        println!("Starting server on {}", address);
+    }
 
-    println!("Starting server on {}", opts.address);
-    warp::serve(routes).run(opts.address);
+    let https_redirect = opts.https_redirect;
+    tokio::run(future::lazy(move || {
+        tokio::spawn(reap_streams(reaper_state));
+        if let Some(redirect_address) = https_redirect {
+            println!("Redirecting HTTP on {} to HTTPS", redirect_address);
+            tokio::spawn(redirect_to_https(redirect_address, address.port(), redirect_rate_limit));
+        }
+        match tls {
+            Some((cert, key)) => future::Either::A(warp::serve(routes).tls(cert, key).bind(address)),
+            None => future::Either::B(warp::serve(routes).bind(address))
+        }
+    }));
 
 }
 
-fn handle_upload<S, B>(stream_id: StreamId, body: S, state: State) -> Result<impl warp::Reply, warp::Rejection>
+/// Answer plain HTTP requests with a redirect to the same place on our HTTPS port:
+fn redirect_to_https<L>(address: SocketAddr, https_port: u16, rate_limit: L) -> impl Future<Item = (), Error = ()>
+    where L: Filter<Extract = (), Error = warp::Rejection> + Clone + Send + Sync + 'static
+{
+    let redirect = warp::path::full()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::optional::<String>("host"))
+        .map(move |path: warp::path::FullPath, query: String, host: Option<String>| {
+            // We can't tell where to send them without knowing which host they asked for:
+            let host = match host {
+                Some(ref host) if !host.is_empty() => host.clone(),
+                _ => return Ok(text_response(StatusCode::BAD_REQUEST, "Missing Host header"))
+            };
+            // Swap the port in the host they asked for for ours, leaving IPv6 addresses intact:
+            let hostname = match host.rfind(':') {
+                Some(idx) if !host[idx..].contains(']') => &host[..idx],
+                _ => &host[..]
+            };
+            let mut location = match https_port {
+                443 => format!("https://{}{}", hostname, path.as_str()),
+                port => format!("https://{}:{}{}", hostname, port, path.as_str())
+            };
+            if !query.is_empty() {
+                location.push('?');
+                location.push_str(&query);
+            }
+            Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(header::LOCATION, location)
+                .body(Body::empty())
+        });
+    warp::serve(rate_limit.and(redirect).recover(handle_rejection)).bind(address)
+}
+
+fn handle_upload<S, B>(stream_id: StreamId, body: S, state: State) -> impl Future<Item = Response<Body>, Error = warp::Rejection>
     where
         S: Stream<Item = B, Error = warp::Error> + Send + 'static,
         B: bytes::Buf
@@ -101,7 +265,7 @@ fn handle_upload<S, B>(stream_id: StreamId, body: S, state: State) -> Result<imp
     let stream_id = stream_id.0;
     let stream_data = match state.streams.take_data(stream_id) {
         Some(s) => s,
-        None => return Err(warp::reject::not_found())
+        None => return future::Either::A(future::err(warp::reject::not_found()))
     };
 
     // Turn our stream of bytes into the format we want to send:
@@ -109,68 +273,780 @@ fn handle_upload<S, B>(stream_id: StreamId, body: S, state: State) -> Result<imp
         .map(|chunk| chunk.bytes().to_owned())
         .map_err(|e| Err::new(format!["Stream error: {}", e]));
 
-    // Stream the bytes to the receiving end, only finishing when it's complete:
-    let s = stream_data
+    // Stream the bytes to the receiving end, only responding when it's complete, so
+    // that we can tell the sender whether it worked:
+    let res = relay_upload(state, stream_id, stream_data, bytes)
+        .then(|res| {
+            let (status, msg) = match res {
+                Ok(()) => (StatusCode::OK, "Transfer successful".to_owned()),
+                Err(e) => (StatusCode::BAD_REQUEST, format!("Transfer failed: {}", e))
+            };
+            let res = Response::builder()
+                .status(status)
+                .body(Body::from(msg))
+                .unwrap();
+            Ok(res)
+        });
+
+    future::Either::B(res)
+
+}
+
+/// Relay the bytes a sender uploads into the stream they're for, and mark the
+/// stream as done (or failed) once they've all been sent on. If anything goes
+/// wrong, including there being more or fewer bytes than the sender said, or them
+/// not matching the digest the sender gave us, we pass the error on so that the
+/// receiver's download is aborted.
+fn relay_upload<S>(state: State, stream_id: Id, stream_data: state::StreamData, bytes: S) -> impl Future<Item = (), Error = Err>
+    where S: Stream<Item = Vec<u8>, Error = Err> + Send + 'static
+{
+    let error_tx = stream_data.clone();
+    let state2 = state.clone();
+    let state3 = state.clone();
+    // A sender that stops part of the way through would otherwise hold the download open forever:
+    let bytes = IdleTimeout::new(bytes, Duration::from_secs(state.options.stream_idle_ttl));
+
+    // The last chunk is only sent on once we've checked every byte, including its own:
+    let bytes = throttle(&state, stream_id, bytes).and_then(move |chunk| {
+        state2.streams.add_received(stream_id, &chunk)
+            .and_then(|()| state2.streams.verify_if_complete(stream_id))
+            .map(|_| Ok(chunk))
+    });
+
+    stream_data
         .sink_map_err(|e| Err::new(format!["Send error: {}", e]))
         .send_all(bytes)
-        .and_then(|_| Ok("Transfer successful"))
-        .into_stream();
+        .and_then(move |_| state3.streams.verify(stream_id))
+        .then(move |res| {
+            state.streams.finish(stream_id, res.is_ok());
+            match res {
+                Ok(()) => future::Either::A(future::ok(())),
+                Err(e) => {
+                    let abort = error_tx.send(Err(e.clone())).then(move |_| -> Result<(), Err> { Err(e) });
+                    future::Either::B(abort)
+                }
+            }
+        })
+}
 
-    // Return the stream, which hopefully will resolve into a body message:
-    let res = Response::builder()
-        .status(200)
-        .body(Body::wrap_stream(s))
-        .unwrap();
+/// Hold each chunk of a stream back for long enough to keep within the bandwidth limits.
+/// Since the channel to the receiver only takes a chunk at a time, this paces the upload too.
+fn throttle<S>(state: &State, stream_id: Id, bytes: S) -> impl Stream<Item = Vec<u8>, Error = Err>
+    where S: Stream<Item = Vec<u8>, Error = Err>
+{
+    let sender_id = state.streams.sender(stream_id).unwrap_or_else(Id::none);
+    let state = state.clone();
+    bytes.and_then(move |chunk| {
+        // Keep asking until there's room for the chunk, since other streams might beat us to it:
+        let state = state.clone();
+        future::loop_fn(chunk, move |chunk| {
+            let delay = state.throttles.delay(sender_id, stream_id, chunk.len());
+            if delay == Duration::from_secs(0) {
+                return future::Either::A(future::ok(future::Loop::Break(chunk)))
+            }
+            let wait = Delay::new(Instant::now() + delay)
+                .map(move |_| future::Loop::Continue(chunk))
+                .map_err(|e| Err::new(format!["Timer error: {}", e]));
+            future::Either::B(wait)
+        })
+    })
+}
 
-    Ok(res)
+/// Fails an upload if we're kept waiting for its next chunk for longer than `ttl`. Unlike
+/// a `Timeout`, the clock only runs while we're waiting on the sender, so the time that
+/// chunks are held back for bandwidth limits, or for a slow receiver, doesn't count.
+struct IdleTimeout<S> {
+    bytes: S,
+    ttl: Duration,
+    // Set once we're waiting for the next chunk:
+    deadline: Option<Delay>
+}
+
+impl <S> IdleTimeout<S> {
+    fn new(bytes: S, ttl: Duration) -> IdleTimeout<S> {
+        IdleTimeout { bytes, ttl, deadline: None }
+    }
+}
+
+impl <S> Stream for IdleTimeout<S>
+    where S: Stream<Item = Vec<u8>, Error = Err>
+{
+    type Item = Vec<u8>;
+    type Error = Err;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, Err> {
+        if let Async::Ready(chunk) = self.bytes.poll()? {
+            self.deadline = None;
+            return Ok(Async::Ready(chunk))
+        }
+        let ttl = self.ttl;
+        let deadline = self.deadline.get_or_insert_with(|| Delay::new(Instant::now() + ttl));
+        match deadline.poll() {
+            Ok(Async::Ready(())) => Err(Err::new("Timed out waiting for more of the upload")),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(Err::new(format!["Timer error: {}", e]))
+        }
+    }
+}
+
+/// Senders can upload a stream as a series of segments, each one a POST saying the
+/// offset that it starts at. We pass on bytes in order and skip any that we already
+/// have, so a segment that fails part way through can just be sent again. Each
+/// response carries an `upload-offset` header saying how many bytes we have so far.
+fn handle_upload_segment<S, B>(stream_id: StreamId, offset: u64, body: S, state: State) -> impl Future<Item = Response<Body>, Error = warp::Rejection>
+    where
+        S: Stream<Item = B, Error = warp::Error> + Send + 'static,
+        B: bytes::Buf
+{
+
+    let stream_id = stream_id.0;
+    let (stream_data, received) = match state.streams.start_segment(stream_id) {
+        Ok(s) => s,
+        Err(e) => {
+            let received = state.streams.received(stream_id).unwrap_or(0);
+            let res = match e {
+                state::SegmentError::NotFound => Err(warp::reject::not_found()),
+                state::SegmentError::Busy => Ok(segment_response(StatusCode::CONFLICT, received, "Another upload to this stream is in progress")),
+                state::SegmentError::Complete => Ok(segment_response(StatusCode::OK, received, "Transfer successful")),
+                state::SegmentError::Failed => Ok(segment_response(StatusCode::GONE, received, "Transfer failed"))
+            };
+            return future::Either::A(future::result(res))
+        }
+    };
+
+    // We can't accept a gap in the data, so the sender needs to resume from where we are:
+    if offset > received {
+        let _ = state.streams.end_segment(stream_id);
+        let res = segment_response(StatusCode::CONFLICT, received, "Segment starts after the bytes received so far");
+        return future::Either::A(future::ok(res))
+    }
 
+    // Skip over any bytes at the start of the segment that we've already passed on:
+    let mut skip = received - offset;
+    let bytes = body
+        .map(|chunk| chunk.bytes().to_owned())
+        .map_err(|e| Err::new(format!["Stream error: {}", e]))
+        .filter_map(move |mut chunk| {
+            let n = std::cmp::min(skip, chunk.len() as u64);
+            chunk.drain(..n as usize);
+            skip -= n;
+            if chunk.is_empty() { None } else { Some(chunk) }
+        });
+    let bytes = IdleTimeout::new(bytes, Duration::from_secs(state.options.stream_idle_ttl));
+    let bytes = throttle(&state, stream_id, bytes);
+
+    // Count each chunk before sending it on, so that bytes beyond the size that the sender
+    // declared never reach the receiver. If the receiver can't take the chunk, the stream
+    // has failed anyway, so it doesn't matter that we've counted it:
+    let state2 = state.clone();
+    let res = bytes
+        .fold(stream_data, move |tx, chunk| {
+            // The chunk that completes the stream is only sent on once every byte checks out:
+            let checked = state2.streams.add_received(stream_id, &chunk)
+                .and_then(|()| state2.streams.verify_if_complete(stream_id));
+            future::result(checked)
+                .and_then(move |()| {
+                    tx.send(Ok(chunk))
+                        .map_err(|e| Err::new(format!["Send error: {}", e]))
+                })
+        })
+        .then(move |res| {
+            let end = state.streams.end_segment(stream_id);
+            let received = state.streams.received(stream_id).unwrap_or(0);
+            let res = match (res, end) {
+                (Ok(_), Ok(true)) => segment_response(StatusCode::OK, received, "Transfer successful"),
+                (Ok(_), Ok(false)) => segment_response(StatusCode::OK, received, "Segment received"),
+                (Ok(_), Err(e)) => segment_response(StatusCode::UNPROCESSABLE_ENTITY, received, e.to_string()),
+                (Err(e), _) if state.streams.state(stream_id) == Some(state::StreamState::Failed) => {
+                    segment_response(StatusCode::GONE, received, format!("Transfer failed: {}", e))
+                },
+                (Err(e), _) => segment_response(StatusCode::BAD_REQUEST, received, e.to_string())
+            };
+            Ok(res)
+        });
+
+    future::Either::B(res)
+
+}
+
+// Turn the chunks sent to a receiver into the file data for them, where an
+// error aborts the download:
+fn file_data<S>(chunks: S) -> FileData
+    where S: Stream<Item = Result<Vec<u8>, Err>, Error = ()> + Send + 'static
+{
+    let data = chunks.then(|res| {
+        match res {
+            Ok(Ok(chunk)) => Ok(chunk),
+            Ok(Err(e)) => { eprintln!("Aborting download: {}", e); Err(()) },
+            Err(()) => Err(())
+        }
+    });
+    Box::new(data)
+}
+
+/// Rejections that we want to answer with something other than warp's default:
+fn handle_rejection(err: warp::Rejection) -> Result<Response<Body>, warp::Rejection> {
+    if err.find_cause::<ratelimit::RateLimited>().is_some() {
+        return Ok(too_many_requests())
+    }
+    Err(err)
+}
+
+fn too_many_requests() -> Response<Body> {
+    text_response(StatusCode::TOO_MANY_REQUESTS, "Too many requests; slow down and try again shortly")
+}
+
+fn text_response(status: StatusCode, msg: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(msg.into())
+        .unwrap()
 }
 
-fn handle_download(sender_id: SenderId, file_id: FileId, state: State) -> impl Future<Item = impl warp::Reply, Error = warp::Rejection> {
+fn segment_response(status: StatusCode, received: u64, msg: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("upload-offset", received)
+        .body(msg.into())
+        .unwrap()
+}
+
+/// Uploads in progress over a sender's websocket, by stream ID:
+type WsUploads = Arc<Mutex<HashMap<Id, mpsc::Sender<Vec<u8>>>>>;
+
+/// Senders can upload file data over their websocket instead of POSTing it. Each
+/// binary message starts with the 16 bytes of the stream ID that it's for, and the
+/// rest is file data. An empty message (just the ID) means the upload is complete.
+/// We only resolve once the data has been accepted, which applies backpressure to
+/// the websocket.
+fn handle_upload_frame(state: &State, uploads: &WsUploads, messages_to_sender: &mpsc::UnboundedSender<MsgToSender>, frame: &[u8]) -> impl Future<Item = (), Error = ()> {
+
+    let stream_id = match Id::from_bytes(frame) {
+        Some(id) => id,
+        None => {
+            eprintln!("Binary message too short to contain a stream ID");
+            return future::Either::A(future::ok(()))
+        }
+    };
+    let data = frame[16..].to_owned();
+
+    let mut current = uploads.lock().unwrap();
+
+    // The first message for a stream starts relaying its data:
+    if !current.contains_key(&stream_id) {
+        let stream_data = match state.streams.take_data(stream_id) {
+            Some(s) => s,
+            None => {
+                eprintln!("Binary message for unknown stream {}", stream_id);
+                return future::Either::A(future::ok(()))
+            }
+        };
+        let (tx, rx) = mpsc::channel(0);
+        let bytes = rx.map_err(|()| Err::new("Websocket upload stream error"));
+        let messages_to_sender = messages_to_sender.clone();
+        tokio::spawn(relay_upload(state.clone(), stream_id, stream_data, bytes).then(move |res| {
+            let error = res.err().map(|e| e.to_string());
+            let _ = messages_to_sender.unbounded_send(MsgToSender::UploadFinished { stream_id, error });
+            Ok(())
+        }));
+        current.insert(stream_id, tx);
+    }
+
+    // An empty message means that we're done. Dropping the channel ends the relay:
+    if data.is_empty() {
+        current.remove(&stream_id);
+        return future::Either::A(future::ok(()))
+    }
+
+    // Otherwise, send the data on, putting the channel back once it's been accepted. If the
+    // relay has gone away (say, because the receiver cancelled their download), we drop the
+    // channel and tell the sender, but keep the websocket up for everything else it's doing:
+    let tx = current.remove(&stream_id).expect("upload channel exists");
+    let uploads = uploads.clone();
+    let messages_to_sender = messages_to_sender.clone();
+    let res = tx.send(data).then(move |res| {
+        match res {
+            Ok(tx) => {
+                uploads.lock().unwrap().insert(stream_id, tx);
+            },
+            Err(e) => {
+                eprintln!("Websocket upload to stream {} failed: {}", stream_id, e);
+                let error = Some("Transfer failed: the download was cancelled".to_owned());
+                let _ = messages_to_sender.unbounded_send(MsgToSender::UploadFinished { stream_id, error });
+            }
+        }
+        Ok(())
+    });
+
+    future::Either::B(res)
+
+}
+
+fn handle_download(sender_id: SenderId, file_id: FileId, query: DownloadQuery, headers: HeaderMap, addr: Option<SocketAddr>, state: State) -> impl Future<Item = impl warp::Reply, Error = warp::Rejection> {
 
     let sender_id = sender_id.0;
     let file_id = file_id.0;
 
-    let (stream_data, data_receiver) = mpsc::channel(0);
-    let (stream_info, info_receiver) = oneshot::channel();
+    let range = headers.get(header::RANGE)
+        .and_then(|r| r.to_str().ok())
+        .and_then(ByteRange::parse);
+    let ip = addr.map(|a| a.ip());
+    let resume = range.map(|r| r.start > 0).unwrap_or(false);
+
+    // Each download is work for the sender, so don't let anybody ask for too many. Seeking
+    // through a video, or resuming a download, asks for more of a file already being
+    // downloaded, one range at a time, so those ranges don't count as more downloads:
+    if !state.senders.is_resume(sender_id, file_id, ip, resume) && !state.addr_download_limits.allow(ip) {
+        return future::Either::A(future::ok(Ok(too_many_requests())))
+    }
+
+    if !authorized(&state, sender_id, query.token.as_ref()) {
+        return future::Either::A(future::ok(Ok(text_response(StatusCode::UNAUTHORIZED, "A valid token is needed to download this file"))))
+    }
+
+    // Senders can limit how long and how many times their files can be downloaded. Don't
+    // bother them if the file isn't available; the download is only counted once it starts:
+    if let Err(reason) = state.senders.check_download(sender_id, file_id, ip, resume) {
+        return future::Either::A(future::ok(Ok(unavailable_response(reason))))
+    }
+
+    // If the sender told us enough about the file in its file list, we can tell whether the
+    // receiver already has it without asking the sender for anything:
+    let declared = state.senders.get(sender_id)
+        .and_then(|s| s.files.into_iter().find(|f| f.id.parse().ok() == Some(file_id)))
+        .and_then(|f| Metadata::declared(&f));
+    if let Some(meta) = declared {
+        if meta.not_modified(&headers) {
+            return future::Either::A(future::ok(not_modified_response(&meta, state.options.compress)))
+        }
+    }
+
+    // Downloads of whole files can share an upload with others downloading the same file:
+    let file = if range.is_none() && state.options.multicast {
+        let state2 = state.clone();
+        future::Either::A(state.multicasts.join(sender_id, file_id, move || {
+            request_file(&state2, sender_id, file_id, None, |_| Ok(()))
+        }))
+    } else {
+        future::Either::B(request_file(&state, sender_id, file_id, range, wanted(&headers, range)))
+    };
+
+    let compression_enabled = state.options.compress;
+    let state2 = state.clone();
+    let state3 = state.clone();
+    let headers2 = headers.clone();
+
+    let res = file
+        .and_then(move |(stream_info, data_receiver)| {
+            // We can only check an If-Range validator once we know about the file. If it
+            // doesn't match, the file has changed, so we ask for the whole thing instead:
+            if range.is_some() && !Metadata::new(&stream_info).if_range_matches(&headers2) {
+                let whole_file = request_file(&state2, sender_id, file_id, None, wanted(&headers2, None))
+                    .map(|(stream_info, data_receiver)| (stream_info, data_receiver, None));
+                future::Either::A(whole_file)
+            } else {
+                future::Either::B(future::ok((stream_info, data_receiver, range)))
+            }
+        })
+        .then(move |res| -> Result<warp::http::Result<Response<Body>>, warp::Rejection> {
+
+            let (stream_info, data_receiver, range) = match res {
+                Ok(file) => file,
+                Err(DownloadError::NotModified(info)) => {
+                    return Ok(not_modified_response(&Metadata::new(&info), compression_enabled))
+                },
+                Err(e) => return Ok(e.response())
+            };
+
+            let body_stream = data_receiver.map_err(|()| Err::new("File stream error"));
+            let meta = Metadata::new(&stream_info);
+            let size = stream_info.size;
+            let digest = stream_info.digest.as_ref().and_then(|d| Sha256Digest::parse(d));
+
+            // Encrypted files have encrypted names, so all we can do is hand over
+            // opaque bytes for the receiver to decrypt:
+            let (name, content_type) = if stream_info.encrypted {
+                (format!("{}.enc", file_id), "application/octet-stream".to_owned())
+            } else {
+                let content_type = mime_guess::guess_mime_type(&stream_info.name).to_string();
+                (stream_info.name, content_type)
+            };
+
+            // Compress whole files on the fly if we're allowed to and it's worth doing. We
+            // never compress partial content, so that ranges always refer to the file bytes.
+            // Encrypted bytes don't compress, so we don't try:
+            let encoding = if compression_enabled && range.is_none() && !stream_info.encrypted && compress::worth_compressing(&content_type) {
+                headers.get(header::ACCEPT_ENCODING)
+                    .and_then(|e| e.to_str().ok())
+                    .and_then(compress::negotiate)
+            } else {
+                None
+            };
+
+            let mut res = Response::builder();
+            res.header("content-type", content_type.as_str())
+               .header("content-disposition", metadata::content_disposition(query.disposition, &name))
+               .header("accept-ranges", "bytes");
+            meta.apply(&mut res, encoding.is_some());
+            // The digest is of the file as the sender has it, which is no longer
+            // what we're sending once it's been compressed:
+            if let (Some(digest), None) = (digest, encoding) {
+                res.header("repr-digest", digest.repr_digest_header())
+                   .header("digest", digest.digest_header());
+            }
+            if compression_enabled {
+                res.header("vary", "accept-encoding");
+            }
+
+            // If the receiver already has this version of the file, there's no need to send it.
+            // Downloads that share an upload only find out about the file once it's under way:
+            if meta.not_modified(&headers) {
+                return Ok(res.status(StatusCode::NOT_MODIFIED).body(Body::empty()));
+            }
+
+            // The bytes are about to start flowing, so this is when the download counts. We
+            // check again, since we might be sending the whole file after all, or somebody
+            // else might have used up the last download while we waited for the sender:
+            let satisfiable = range.map(|r| r.resolve(size).is_some()).unwrap_or(true);
+            if satisfiable {
+                let resume = range.map(|r| r.start > 0).unwrap_or(false);
+                match state3.senders.start_download(sender_id, file_id, ip, resume) {
+                    Ok(unavailable) => notify_unavailable(&state3, sender_id, unavailable),
+                    Err(reason) => return Ok(Ok(unavailable_response(reason)))
+                }
+            }
+
+            // stream the response (or the part of it that was asked for) back to the receiver:
+            let res = match range.map(|r| r.resolve(size)) {
+                None => match encoding {
+                    // We don't know the compressed size up front, so no content-length
+                    // here; hyper falls back to a chunked response:
+                    Some(encoding) => {
+                        res.status(StatusCode::OK)
+                           .header("content-encoding", encoding.name())
+                           .body(Body::wrap_stream(compress::compress(encoding, body_stream)))
+                    },
+                    None => {
+                        res.status(StatusCode::OK)
+                           .header("content-length", size)
+                           .body(Body::wrap_stream(body_stream))
+                    }
+                },
+                Some(Some((start, end))) => {
+                    res.status(StatusCode::PARTIAL_CONTENT)
+                       .header("content-range", format!("bytes {}-{}/{}", start, end, size))
+                       .header("content-length", end - start + 1)
+                       .body(Body::wrap_stream(body_stream))
+                },
+                Some(None) => {
+                    res.status(StatusCode::RANGE_NOT_SATISFIABLE)
+                       .header("content-range", format!("bytes */{}", size))
+                       .body(Body::empty())
+                }
+            };
+
+            Ok(res)
+
+        });
+
+    future::Either::B(res)
+
+}
 
-    let stream_id = state.streams.add(stream_data, stream_info);
+fn handle_archive(sender_id: SenderId, req: ArchiveRequest, query: ArchiveQuery, addr: Option<SocketAddr>, state: State) -> Result<impl warp::Reply, warp::Rejection> {
 
+    let sender_id = sender_id.0;
+    if !state.addr_download_limits.allow(addr.map(|a| a.ip())) {
+        return Ok(Ok(too_many_requests()))
+    }
     let sender = match state.senders.get(sender_id) {
         Some(s) => s,
-        None => return future::Either::A(future::err(warp::reject::not_found()))
+        None => return Err(warp::reject::not_found())
+    };
+
+    if !authorized(&state, sender_id, query.token.as_ref()) {
+        return Ok(Ok(text_response(StatusCode::UNAUTHORIZED, "A valid token is needed to download these files")))
+    }
+
+    // Only allow files that the sender has told us about, so that we don't end
+    // up waiting forever for a file that the sender doesn't have:
+    let known_ids: Vec<Id> = sender.files.iter().filter_map(|f| f.id.parse().ok()).collect();
+    let (file_ids, all_files) = match req.files {
+        FileSelection::All => (known_ids, true),
+        FileSelection::Only(ids) => {
+            if ids.iter().any(|id| !known_ids.contains(id)) {
+                return Err(warp::reject::not_found())
+            }
+            (ids, false)
+        }
+    };
+
+    // Leave out any files that can't be downloaded any more. If particular files were
+    // asked for, they all need to be there:
+    let ip = addr.map(|a| a.ip());
+    let mut available = Vec::with_capacity(file_ids.len());
+    for &file_id in &file_ids {
+        match state.senders.check_download(sender_id, file_id, ip, false) {
+            Ok(()) => available.push(file_id),
+            Err(reason) if !all_files => return Ok(Ok(unavailable_response(reason))),
+            Err(_) => {}
+        }
+    }
+    let file_ids = available;
+
+    // Ask the sender for each file in turn, writing it into the archive as it arrives. Each
+    // file counts as a download once its bytes start arriving:
+    let fetch = move |file_id| {
+        let state2 = state.clone();
+        request_file(&state, sender_id, file_id, None, |_| Ok(()))
+            .map_err(|e| Err::new(e.to_string()))
+            .and_then(move |(info, data)| {
+                match state2.senders.start_download(sender_id, file_id, ip, false) {
+                    Ok(unavailable) => notify_unavailable(&state2, sender_id, unavailable),
+                    Err(reason) => return Err(Err::new(format!("File {} is no longer available: {:?}", file_id, reason)))
+                }
+                Ok((info, data.map_err(|()| Err::new("File stream error"))))
+            })
+    };
+
+    let body: Box<dyn Stream<Item = Vec<u8>, Error = Err> + Send> = match req.kind {
+        Kind::Zip => Box::new(Archive::new(archive::Zip::new(), file_ids, fetch)),
+        Kind::Tar => Box::new(Archive::new(archive::Tar::new(), file_ids, fetch)),
+        Kind::TarGz => Box::new(compress::compress(Encoding::Gzip, Archive::new(archive::Tar::new(), file_ids, fetch)))
+    };
+
+    let res = Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", req.kind.content_type())
+        .body(Body::wrap_stream(body));
+
+    Ok(res)
+
+}
+
+/// Ask a sender to upload a file (or a range of bytes from it) to us. This resolves to
+/// the file info once the sender has acknowledged the request and started uploading, along
+/// with a stream of the bytes that they upload. Once we know about the file, `wanted` can
+/// decide that we don't need it after all, in which case the upload is called off and we
+/// hand back the error it gives without waiting for any bytes.
+fn request_file<W>(state: &State, sender_id: Id, file_id: Id, range: Option<ByteRange>, wanted: W) -> impl Future<Item = (FileInfoForStream, FileData), Error = DownloadError>
+    where W: FnOnce(&FileInfoForStream) -> Result<(), DownloadError> + Send + 'static
+{
+
+    let sender = match state.senders.get(sender_id) {
+        Some(s) => s,
+        None => return future::Either::A(future::err(DownloadError::SenderNotFound))
     };
 
+    // If the sender is suspended, the request waits for it to reconnect, so give it longer to answer:
+    let mut ack_timeout = Duration::from_secs(state.options.ack_timeout);
+    if sender.suspended.is_some() {
+        ack_timeout += Duration::from_secs(state.options.sender_grace);
+    }
+    let upload_timeout = Duration::from_secs(state.options.upload_timeout);
+
+    let (stream_data, data_receiver) = mpsc::channel(0);
+    let (stream_info, info_receiver) = oneshot::channel();
+
+    let stream_id = state.streams.add(sender_id, Some(file_id), range, stream_data, stream_info);
+    let state = state.clone();
+
     let msg = MsgToSender::PleaseUpload {
         file_id: file_id,
-        stream_id: stream_id
+        stream_id: stream_id,
+        offset: range.map(|r| r.start).unwrap_or(0),
+        length: range.and_then(|r| r.length())
     };
+    state.senders.send(sender_id, msg);
+
+    // Wait for the sender to acknowledge the request:
+    let res = Timeout::new(info_receiver, ack_timeout)
+        .then(|res| {
+            match res {
+                Ok(Ok(info)) => Ok(info),
+                Ok(Err(reason)) => Err(DownloadError::from(reason)),
+                Err(ref e) if e.is_elapsed() => Err(DownloadError::Timeout),
+                Err(_) => Err(DownloadError::Failed("Sender went away".to_owned()))
+            }
+        })
+        // Wait for the first bytes to arrive, so that we can still tell the
+        // receiver if the sender never gets round to uploading anything:
+        .and_then(move |info| {
+            if let Err(e) = wanted(&info) {
+                return future::Either::A(future::err(e))
+            }
+            let first_bytes = Timeout::new(data_receiver.into_future(), upload_timeout)
+                .map(move |(first, rest)| {
+                    (info, file_data(stream::iter_ok(first).chain(rest)))
+                })
+                .map_err(|e| {
+                    if e.is_elapsed() { DownloadError::Timeout }
+                    else { DownloadError::Failed("Upload failed".to_owned()) }
+                });
+            future::Either::B(first_bytes)
+        })
+        .map_err(move |e| {
+            match e {
+                // Nothing went wrong; we just don't need the file, so let the sender know it can stop:
+                DownloadError::NotModified(_) | DownloadError::RangeNotSatisfiable(_) => {
+                    state.streams.finish(stream_id, true);
+                    state.senders.send(sender_id, MsgToSender::UploadFinished { stream_id, error: None });
+                },
+                _ => state.streams.finish(stream_id, false)
+            }
+            e
+        });
 
-    let res = sender.tx
-        .send(msg)
-        .map_err(|e| warp::reject::server_error().with(e))
-        .and_then(|_| info_receiver.map_err(|e| warp::reject::server_error().with(e)))
-        .and_then(|stream_info| {
+    future::Either::B(res)
 
-            let body_stream = data_receiver.map_err(|()| Err::boxed_never());
-            let name = stream_info.name;
-            let size = stream_info.size;
+}
 
-            // stream the response back to the receiver:
-            let res = Response::builder()
-                .status(StatusCode::OK)
-                .header("content-type", mime_guess::guess_mime_type(&name).as_ref())
-                .header("content-length", size)
-                .body(Body::wrap_stream(body_stream));
+// Downloads don't need a file that the receiver already has the latest version of, or
+// a range of bytes that isn't in it. A range that's only wanted if the file hasn't changed
+// is left alone if it has, since the whole file is asked for instead:
+fn wanted(headers: &HeaderMap, range: Option<ByteRange>) -> impl FnOnce(&FileInfoForStream) -> Result<(), DownloadError> + Send + 'static {
+    let headers = headers.clone();
+    move |info| {
+        let meta = Metadata::new(info);
+        if meta.not_modified(&headers) {
+            return Err(DownloadError::NotModified(Box::new(info.clone())))
+        }
+        match range {
+            Some(range) if meta.if_range_matches(&headers) && range.resolve(info.size).is_none() => {
+                Err(DownloadError::RangeNotSatisfiable(info.size))
+            },
+            _ => Ok(())
+        }
+    }
+}
 
-            Ok(res)
+/// Visitors can offer a file to a sender that has opened its drop box. We tell the sender
+/// about the file, and if they accept it, relay the visitor's upload to them as they
+/// download it. The visitor finds out how it went once the transfer is over.
+fn handle_drop_box_upload<S, B>(sender_id: SenderId, query: DropBoxQuery, headers: HeaderMap, body: S, state: State) -> impl Future<Item = Response<Body>, Error = warp::Rejection>
+    where
+        S: Stream<Item = B, Error = warp::Error> + Send + 'static,
+        B: bytes::Buf
+{
+
+    let sender_id = sender_id.0;
+    match state.senders.get(sender_id) {
+        Some(ref sender) if sender.drop_box => {},
+        _ => return future::Either::A(future::err(warp::reject::not_found()))
+    }
 
+    // We need to tell the sender how big the file is before they accept it:
+    let size = headers.get(header::CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok())
+        .and_then(|l| l.parse().ok());
+    let size = match size {
+        Some(size) => size,
+        None => return future::Either::A(future::ok(text_response(StatusCode::LENGTH_REQUIRED, "Content-Length is required")))
+    };
+
+    let info = FileInfoForStream {
+        name: query.name,
+        size: size,
+        modified: None,
+        hash: None,
+        digest: None,
+        encrypted: false
+    };
+
+    // The visitor's upload goes through a stream like any other, so that
+    // it's relayed and checked in just the same way:
+    let (stream_data, data_receiver) = mpsc::channel(0);
+    let (stream_info, _) = oneshot::channel();
+    let stream_id = state.streams.add(sender_id, None, None, stream_data, stream_info);
+    let _ = state.streams.acknowledge(stream_id, &info);
+
+    let (decision, decision_receiver) = oneshot::channel();
+    let (picked_up, picked_up_receiver) = oneshot::channel();
+    state.incoming.add(stream_id, state::Offer {
+        sender_id: sender_id,
+        info: info.clone(),
+        decision: Some(decision),
+        picked_up: Some(picked_up),
+        data: file_data(data_receiver)
+    });
+    state.senders.send(sender_id, MsgToSender::IncomingFile { stream_id, info });
+
+    let ack_timeout = Duration::from_secs(state.options.ack_timeout);
+    let upload_timeout = Duration::from_secs(state.options.upload_timeout);
+    let state2 = state.clone();
+
+    let bytes = body
+        .map(|chunk| chunk.bytes().to_owned())
+        .map_err(|e| Err::new(format!["Stream error: {}", e]));
+
+    let res = Timeout::new(decision_receiver, ack_timeout)
+        // Wait for the sender to accept the file:
+        .then(|res| {
+            match res {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(reason)) => {
+                    let reason = reason.unwrap_or_else(|| "The file was declined".to_owned());
+                    Err(text_response(StatusCode::FORBIDDEN, reason))
+                },
+                Err(ref e) if e.is_elapsed() => Err(text_response(StatusCode::GATEWAY_TIMEOUT, "Timed out waiting for the file to be accepted")),
+                Err(_) => Err(text_response(StatusCode::SERVICE_UNAVAILABLE, "The recipient went away"))
+            }
+        })
+        // Wait for them to start downloading it:
+        .and_then(move |_| {
+            Timeout::new(picked_up_receiver, upload_timeout).map_err(|e| {
+                if e.is_elapsed() { text_response(StatusCode::GATEWAY_TIMEOUT, "Timed out waiting for the recipient to start downloading") }
+                else { text_response(StatusCode::SERVICE_UNAVAILABLE, "The recipient went away") }
+            })
+        })
+        // Then relay the file to them:
+        .and_then(move |_| {
+            let stream_data = match state2.streams.take_data(stream_id) {
+                Some(s) => s,
+                None => return future::Either::A(future::err(text_response(StatusCode::GONE, "Transfer failed")))
+            };
+            let relay = relay_upload(state2, stream_id, stream_data, bytes).then(|res| {
+                match res {
+                    Ok(()) => Ok(text_response(StatusCode::OK, "Transfer successful")),
+                    Err(e) => Err(text_response(StatusCode::BAD_REQUEST, format!("Transfer failed: {}", e)))
+                }
+            });
+            future::Either::B(relay)
+        })
+        .then(move |res| -> Result<Response<Body>, warp::Rejection> {
+            if res.is_err() {
+                state.incoming.remove(stream_id);
+                state.streams.finish(stream_id, false);
+            }
+            Ok(res.unwrap_or_else(|res| res))
         });
 
     future::Either::B(res)
 
 }
 
+/// Senders download the files offered to their drop box from here, which also
+/// accepts the file if they haven't already done so.
+fn handle_incoming_download(stream_id: StreamId, state: State) -> Result<impl warp::Reply, warp::Rejection> {
+
+    let (info, data) = match state.incoming.take(stream_id.0) {
+        Some(offer) => offer,
+        None => return Err(warp::reject::not_found())
+    };
+
+    let body = data.map_err(|()| Err::new("File stream error"));
+    let res = Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", mime_guess::guess_mime_type(&info.name).as_ref())
+        .header("content-disposition", metadata::content_disposition(Disposition::Attachment, &info.name))
+        .header("content-length", info.size)
+        .body(Body::wrap_stream(body));
+
+    Ok(res)
+
+}
+
 fn handle_sender_ws(ws: WebSocket, state: State) -> impl Future<Item = (), Error = ()> {
 
     // Get hold of a transmitter and receiver of messages:
@@ -182,8 +1058,11 @@ fn handle_sender_ws(ws: WebSocket, state: State) -> impl Future<Item = (), Error
     // convert rx to websocket messages and pipe to tx:
     let pipe = with_serialized_sink(tx).sink_map_err(|_| ()).send_all(rx);
 
-    // keep track of sender ID, once it's known, here:
-    let shared_sender_id = Arc::new(RwLock::new(None as Option<id::Id>));
+    // keep track of sender ID and the reconnect token we gave out for it, once known, here:
+    let shared_sender_id = Arc::new(RwLock::new(None as Option<(id::Id, String)>));
+
+    // keep track of any uploads happening over the websocket:
+    let uploads: WsUploads = Arc::new(Mutex::new(HashMap::new()));
 
     // clones to move into "then" closure:
     let shared_sender_id2 = shared_sender_id.clone();
@@ -198,19 +1077,25 @@ fn handle_sender_ws(ws: WebSocket, state: State) -> impl Future<Item = (), Error
         // Each time a message comes in, handle it:
         .for_each(move |msg| {
 
-            let maybe_sender_id = shared_sender_id.read().unwrap().clone();
+            // Binary messages contain file data being uploaded:
+            if msg.is_binary() {
+                return future::Either::A(handle_upload_frame(&state, &uploads, &messages_to_sender, msg.as_bytes()));
+            }
 
-            println!("From sender {}: {:?}", maybe_sender_id.unwrap_or_else(Id::none), msg);
+            let maybe_sender_id = shared_sender_id.read().unwrap().as_ref().map(|(id, _)| *id);
 
             let msg_str = msg.to_str().unwrap_or("");
-            let msg = match serde_json::from_str(msg_str) {
+            let msg: MsgFromSender = match serde_json::from_str(msg_str) {
                 Ok(s) => s,
                 Err(e) => {
                     eprintln!("Error decoding message {}: {}", msg_str, e);
-                    return Ok(())
+                    return future::Either::B(future::Either::B(future::ok(())))
                 }
             };
 
+            // Don't log passwords:
+            println!("From sender {}: {:?}", maybe_sender_id.unwrap_or_else(Id::none), msg.redacted());
+
             let send_message = |msg: MsgToReceiver, receiver_id: Option<Id>| {
                 if let Some(receiver_id) = receiver_id {
                     state.receivers.write().send_one(receiver_id, msg);
@@ -221,44 +1106,143 @@ fn handle_sender_ws(ws: WebSocket, state: State) -> impl Future<Item = (), Error
 
             use crate::messages::MsgFromSender::*;
             match msg {
-                Handshake { id: maybe_id } => {
-                    match maybe_sender_id {
-                        Some(current_id) => {
+                Handshake { id: maybe_id, reconnect_token, password } => {
+                    let current = shared_sender_id.read().unwrap().clone();
+                    match current {
+                        Some((current_id, current_token)) => {
                             // If we have done a handshake, don't allow another one and return the same ID.
                             // there is no reason we should want to re-handshake unless we lose our connection..
-                            let _ = messages_to_sender.unbounded_send(MsgToSender::HandshakeAck{ id: current_id });
+                            let _ = messages_to_sender.unbounded_send(MsgToSender::HandshakeAck{ id: current_id, reconnect_token: current_token });
                         },
                         None => {
-                            let sender_id = state.senders.add(messages_to_sender.clone(), maybe_id);
-                            *shared_sender_id.write().unwrap() = Some(sender_id);
-                            let _ = messages_to_sender.unbounded_send(MsgToSender::HandshakeAck{ id: sender_id });
+                            // A sender that reclaims its ID with the same password keeps the same hash,
+                            // so that the download tokens handed out for it stay valid. Hashing is slow,
+                            // so it's done off to the side:
+                            let token = reconnect_token;
+                            let existing = maybe_id.and_then(|id| state.senders.reclaimable_password(id, token.as_ref().map(|t| t.as_str())));
+                            let password = password.filter(|p| !p.is_empty());
+                            let hashed = blocking(move || password.map(|password| match existing {
+                                Some(hash) if hash.verify(&password) => hash,
+                                _ => auth::PasswordHash::new(&password)
+                            }));
+
+                            let state = state.clone();
+                            let messages_to_sender = messages_to_sender.clone();
+                            let shared_sender_id = shared_sender_id.clone();
+                            let handshake = hashed.and_then(move |password| {
+                                match state.senders.add(messages_to_sender.clone(), maybe_id, token.as_ref().map(|t| t.as_str()), password) {
+                                    Ok((sender_id, reconnect_token)) => {
+                                        *shared_sender_id.write().unwrap() = Some((sender_id, reconnect_token.clone()));
+                                        let _ = messages_to_sender.unbounded_send(MsgToSender::HandshakeAck{ id: sender_id, reconnect_token });
+                                        // Check the passwords of any receivers that connected before we did, now that
+                                        // we know what it should be. Let the rest know we're here, get them our file
+                                        // list and let them know about anything they wanted to download:
+                                        let waiting = state.receivers.write().waiting_for(sender_id);
+                                        let checks: Vec<_> = waiting.into_iter().map(|(receiver_id, w)| {
+                                            check_password(&state, w.addr, sender_id, w.password)
+                                                .then(move |checked| -> Result<_, ()> { Ok((receiver_id, checked)) })
+                                        }).collect();
+                                        let welcome = future::join_all(checks).map(move |checked| {
+                                            // Receivers that only knew an old password, or none, are turned away here:
+                                            let password = state.senders.get(sender_id).and_then(|s| s.password);
+                                            let online = state.receivers.write().sender_online(sender_id, password.as_ref(), &checked);
+                                            for (receiver_id, queued) in online {
+                                                let token = download_token(&state, sender_id, password.as_ref());
+                                                let mut receivers = state.receivers.write();
+                                                receivers.send_one(receiver_id, MsgToReceiver::SenderOnline { token });
+                                                for file_id in queued {
+                                                    receivers.send_one(receiver_id, MsgToReceiver::DownloadReady { file_id });
+                                                }
+                                                drop(receivers);
+                                                state.senders.send(sender_id, MsgToSender::PleaseFileList { receiver_id });
+                                            }
+                                        });
+                                        future::Either::A(welcome)
+                                    },
+                                    Err(reason) => {
+                                        let _ = messages_to_sender.unbounded_send(MsgToSender::HandshakeRejected{ reason });
+                                        future::Either::B(future::ok(()))
+                                    }
+                                }
+                            });
+                            return future::Either::B(future::Either::A(handshake))
                         }
                     }
 
                 },
                 PleaseUploadAck { stream_id, info } => {
+                    if let Some(chan) = state.streams.acknowledge(stream_id, &info) {
+                        let _ = chan.send(Ok(info));
+                    }
+                },
+                PleaseUploadNack { stream_id, reason } => {
                     if let Some(chan) = state.streams.take_info(stream_id) {
-                        let _ = chan.send(info);
+                        let _ = chan.send(Err(reason));
+                    }
+                },
+                DropBox { enabled } => {
+                    if let Some(sender_id) = maybe_sender_id {
+                        state.senders.set_drop_box(sender_id, enabled);
+                    }
+                },
+                AcceptIncoming { stream_id } => {
+                    if let Some(sender_id) = maybe_sender_id {
+                        state.incoming.accept(sender_id, stream_id);
+                    }
+                },
+                RejectIncoming { stream_id, reason } => {
+                    if let Some(sender_id) = maybe_sender_id {
+                        if state.incoming.reject(sender_id, stream_id, reason) {
+                            state.streams.finish(stream_id, false);
+                        }
+                    }
+                },
+                SetLimits { file_id, expires_in, max_downloads } => {
+                    if let Some(sender_id) = maybe_sender_id {
+                        let limits = state::Limits::new(expires_in.map(Duration::from_secs), max_downloads);
+                        state.senders.set_limits(sender_id, file_id, limits);
                     }
                 },
                 FilesAdded { receiver_id, files } => {
+                    if let Some(sender_id) = maybe_sender_id {
+                        state.senders.update_files(sender_id, |current| {
+                            current.retain(|f| !files.iter().any(|a| a.id == f.id));
+                            current.extend(files.iter().cloned());
+                        });
+                    }
                     send_message(MsgToReceiver::FilesAdded { files }, receiver_id);
                 },
                 FilesRemoved { receiver_id, files } => {
+                    if let Some(sender_id) = maybe_sender_id {
+                        state.senders.update_files(sender_id, |current| {
+                            current.retain(|f| !files.iter().any(|r| r.id == f.id));
+                        });
+                    }
                     send_message(MsgToReceiver::FilesRemoved { files }, receiver_id);
                 },
                 FileList { receiver_id, files } => {
+                    if let Some(sender_id) = maybe_sender_id {
+                        state.senders.update_files(sender_id, |current| {
+                            *current = files.clone();
+                        });
+                    }
                     send_message(MsgToReceiver::FileList { files }, receiver_id);
                 }
             }
 
-            Ok(())
+            future::Either::B(future::Either::B(future::ok(())))
 
         })
         // When the connection is closed, for_each ends and we clean up:
         .then(move |res| {
-            if let Some(sender_id) = *shared_sender_id2.read().unwrap() {
-                state2.senders.remove(sender_id);
+            // Give the sender a chance to reconnect before its share goes away. Requests it
+            // hadn't got round to are asked again if it does. Nothing happens if a newer
+            // connection has already reclaimed our ID:
+            if let Some((sender_id, token)) = shared_sender_id2.read().unwrap().as_ref() {
+                let unanswered = state2.streams.pending_requests(*sender_id);
+                if state2.senders.suspend(*sender_id, token, unanswered) {
+                    state2.receivers.write().send_if(MsgToReceiver::SenderOffline, |r| r.sender_id == *sender_id);
+                }
             }
             res
         });
@@ -267,7 +1251,7 @@ fn handle_sender_ws(ws: WebSocket, state: State) -> impl Future<Item = (), Error
     from_sender.join(pipe).map(|_| ())
 }
 
-fn handle_receiver_ws(ws: WebSocket, state: State) -> impl Future<Item = (), Error = ()> {
+fn handle_receiver_ws(ws: WebSocket, addr: Option<SocketAddr>, state: State) -> impl Future<Item = (), Error = ()> {
 
     // Get hold of a transmitter and receiver of messages:
     let (tx, messages_from_receiver) = ws.split();
@@ -281,10 +1265,17 @@ fn handle_receiver_ws(ws: WebSocket, state: State) -> impl Future<Item = (), Err
     // keep track of sender ID and receiver ID, once it's known, here:
     let shared_ids = Arc::new(RwLock::new(None));
 
+    // and the reconnect token we gave out for the receiver ID:
+    let shared_reconnect_token = Arc::new(RwLock::new(None as Option<String>));
+
     // clones to move into "then" closure:
     let shared_ids2 = shared_ids.clone();
+    let shared_reconnect_token2 = shared_reconnect_token.clone();
     let state2 = state.clone();
 
+    // limit how quickly messages can arrive on this connection:
+    let mut message_limit = ratelimit::Bucket::new(state.options.ws_message_rate, state.options.ws_message_burst);
+
     // handle each message we receive from the sender:
     let from_sender = messages_from_receiver
         // Catch and report any errors:
@@ -296,53 +1287,124 @@ fn handle_receiver_ws(ws: WebSocket, state: State) -> impl Future<Item = (), Err
 
             let maybe_receiver_id = shared_ids.read().unwrap().clone().map(|(_, r)| r);
 
-            println!("From receiver {}: {:?}", maybe_receiver_id.unwrap_or_else(Id::none), raw_msg);
+            // Every message is likely to be work for the sender, so ignore any that come too quickly:
+            if !message_limit.take() {
+                let _ = messages_to_receiver.unbounded_send(MsgToReceiver::RateLimited);
+                return future::Either::B(future::ok(()))
+            }
 
             let msg_str = raw_msg.to_str().unwrap_or("");
-            let msg = match serde_json::from_str(msg_str) {
+            let msg: MsgFromReceiver = match serde_json::from_str(msg_str) {
                 Ok(s) => s,
                 Err(e) => {
                     eprintln!("Error decoding message {}: {}", msg_str, e);
-                    return Ok(())
+                    return future::Either::B(future::ok(()))
                 }
             };
 
+            // Don't log passwords:
+            println!("From receiver {}: {:?}", maybe_receiver_id.unwrap_or_else(Id::none), msg.redacted());
+
             use crate::messages::MsgFromReceiver::*;
             match msg {
-                Handshake { sender_id, id: maybe_id } => {
-                    match maybe_receiver_id {
-                        Some(current_receiver_id) => {
+                Handshake { sender_id, id: maybe_id, reconnect_token, password } => {
+                    // We might have been turned away since our last handshake, when the sender
+                    // turned up with a password, so check we're still here. If the sender's password
+                    // has changed since we proved we knew it, we have to prove we know the new one:
+                    let current_ids = (*shared_ids.read().unwrap()).and_then(|(sender_id, receiver_id)| {
+                        let receiver = state.receivers.get(receiver_id)?;
+                        let password = state.senders.get(sender_id).and_then(|s| s.password);
+                        if password.is_some() && receiver.password != password { return None }
+                        Some((sender_id, receiver_id, password))
+                    });
+                    match current_ids {
+                        Some((current_sender_id, current_receiver_id, password)) => {
                             // If we have done a handshake, don't allow another one and return the same ID.
                             // there is no reason we should want to re-handshake unless we lose our connection..
-                            let _ = messages_to_receiver.unbounded_send(MsgToReceiver::HandshakeAck{ id: current_receiver_id });
+                            // It's a good way to get a fresh download token though:
+                            let token = download_token(&state, current_sender_id, password.as_ref());
+                            let reconnect_token = shared_reconnect_token.read().unwrap().clone().unwrap_or_default();
+                            let _ = messages_to_receiver.unbounded_send(MsgToReceiver::HandshakeAck{ id: current_receiver_id, reconnect_token, token });
                         },
                         None => {
-                            let receiver_id = state.receivers.add(sender_id, messages_to_receiver.clone(), maybe_id);
-                            *shared_ids.write().unwrap() = Some((sender_id, receiver_id));
-                            let _ = messages_to_receiver.unbounded_send(MsgToReceiver::HandshakeAck{ id: receiver_id });
+                            // If the sender isn't here yet, we wait for it, holding on to the password we
+                            // were given to check once the sender arrives and we know what it should be:
+                            let waiting = match state.senders.get(sender_id) {
+                                Some(_) => None,
+                                None => Some(state::Waiting { addr, password: password.clone() })
+                            };
+                            let state = state.clone();
+                            let messages_to_receiver = messages_to_receiver.clone();
+                            let shared_ids = shared_ids.clone();
+                            let shared_reconnect_token = shared_reconnect_token.clone();
+                            let handshake = check_password(&state, addr, sender_id, password).then(move |checked| -> Result<(), ()> {
+                                let password = match checked {
+                                    Ok(password) => password,
+                                    Err(reason) => {
+                                        let _ = messages_to_receiver.unbounded_send(MsgToReceiver::HandshakeRejected{ reason });
+                                        return Ok(())
+                                    }
+                                };
+                                let (receiver_id, reconnect_token) = match state.receivers.add(sender_id, messages_to_receiver.clone(), maybe_id, reconnect_token.as_ref().map(|t| t.as_str()), waiting, password.clone()) {
+                                    Ok(added) => added,
+                                    Err(reason) => {
+                                        let _ = messages_to_receiver.unbounded_send(MsgToReceiver::HandshakeRejected{ reason });
+                                        return Ok(())
+                                    }
+                                };
+                                *shared_ids.write().unwrap() = Some((sender_id, receiver_id));
+                                *shared_reconnect_token.write().unwrap() = Some(reconnect_token.clone());
+                                let token = download_token(&state, sender_id, password.as_ref());
+                                let _ = messages_to_receiver.unbounded_send(MsgToReceiver::HandshakeAck{ id: receiver_id, reconnect_token, token });
+                                // The sender might not be here, in which case downloads wait for it:
+                                if !sender_online(&state, sender_id) {
+                                    let _ = messages_to_receiver.unbounded_send(MsgToReceiver::SenderOffline);
+                                }
+                                Ok(())
+                            });
+                            return future::Either::A(handshake)
                         }
                     }
 
                 },
                 PleaseUpload { file_id, stream_id } => {
-                    if let Some((sender_id, _receiver_id)) = *shared_ids.read().unwrap() {
-                        state.senders.send(sender_id, MsgToSender::PleaseUpload{ file_id, stream_id });
+                    if let Some((sender_id, receiver_id)) = *shared_ids.read().unwrap() {
+                        if !state.receiver_download_limits.allow(receiver_id) {
+                            let _ = messages_to_receiver.unbounded_send(MsgToReceiver::RateLimited);
+                            return future::Either::B(future::ok(()))
+                        }
+                        state.senders.send(sender_id, MsgToSender::PleaseUpload{ file_id, stream_id, offset: 0, length: None });
                     }
                 },
                 PleaseFileList => {
                     if let Some((sender_id, receiver_id)) = *shared_ids.read().unwrap() {
                         state.senders.send(sender_id, MsgToSender::PleaseFileList{ receiver_id });
                     }
+                },
+                QueueDownload { file_id } => {
+                    if let Some((sender_id, receiver_id)) = *shared_ids.read().unwrap() {
+                        if !state.receiver_download_limits.allow(receiver_id) {
+                            let _ = messages_to_receiver.unbounded_send(MsgToReceiver::RateLimited);
+                            return future::Either::B(future::ok(()))
+                        }
+                        if sender_online(&state, sender_id) {
+                            state.receivers.write().send_one(receiver_id, MsgToReceiver::DownloadReady{ file_id });
+                        } else {
+                            state.receivers.write().queue_download(receiver_id, file_id);
+                        }
+                    }
                 }
             }
 
-            Ok(())
+            future::Either::B(future::ok(()))
 
         })
         // When the connection is closed, for_each ends and we clean up:
         .then(move |res| {
-            if let Some((_sender_id, receiver_id)) = *shared_ids2.read().unwrap() {
-                state2.receivers.write().remove(receiver_id);
+            // Only tidy up if a newer connection hasn't reclaimed our ID:
+            let ids = *shared_ids2.read().unwrap();
+            if let (Some((_sender_id, receiver_id)), Some(token)) = (ids, shared_reconnect_token2.read().unwrap().as_ref()) {
+                state2.receivers.write().remove(receiver_id, token);
             }
             res
         });
@@ -351,6 +1413,137 @@ fn handle_receiver_ws(ws: WebSocket, state: State) -> impl Future<Item = (), Err
     from_sender.join(pipe).map(|_| ())
 }
 
+/// Is a sender connected, rather than suspended or not here at all?
+fn sender_online(state: &State, sender_id: Id) -> bool {
+    state.senders.get(sender_id).map(|s| s.suspended.is_none()).unwrap_or(false)
+}
+
+/// Does a receiver know the password for this sender's share, if it has one? Each address,
+/// and each share, only gets so many wrong guesses in a while, to make brute forcing it slow.
+/// Hands back the hash that the password was checked against, if there was one.
+fn check_password(state: &State, addr: Option<SocketAddr>, sender_id: Id, password: Option<String>) -> impl Future<Item = Option<auth::PasswordHash>, Error = RejectReason> {
+    let hash = match state.senders.get(sender_id).and_then(|s| s.password) {
+        Some(hash) => hash,
+        None => return future::Either::A(future::ok(None))
+    };
+    let password = match password {
+        Some(password) => password,
+        None => return future::Either::A(future::err(RejectReason::PasswordRequired))
+    };
+    let ip = addr.map(|a| a.ip());
+    if !state.attempts.attempt(ip, sender_id) {
+        return future::Either::A(future::err(RejectReason::TooManyAttempts))
+    }
+    let state = state.clone();
+    let checking = hash.clone();
+    let checked = blocking(move || checking.verify(&password)).then(move |verified| {
+        if verified == Ok(true) {
+            state.attempts.succeeded(ip, sender_id);
+            Ok(Some(hash))
+        } else {
+            Err(RejectReason::PasswordIncorrect)
+        }
+    });
+    future::Either::B(checked)
+}
+
+/// Run something slow, like hashing a password, on a thread that's allowed to block,
+/// rather than holding up everything else waiting on this one.
+fn blocking<T, F>(f: F) -> impl Future<Item = T, Error = ()>
+    where F: FnOnce() -> T
+{
+    let mut f = Some(f);
+    future::poll_fn(move || {
+        // The function is only called once there's a thread free to block on:
+        tokio_threadpool::blocking(|| (f.take().expect("polled after completion"))())
+            .map_err(|e| eprintln!("Can't block: {}", e))
+    })
+}
+
+// Receivers of a password protected share need a token to download its files, which
+// is only good for the password hash they proved they know the password for:
+fn download_token(state: &State, sender_id: Id, password: Option<&auth::PasswordHash>) -> Option<String> {
+    password.map(|hash| state.tokens.issue(sender_id, hash))
+}
+
+// Is a download from this sender allowed with the token given?
+fn authorized(state: &State, sender_id: Id, token: Option<&String>) -> bool {
+    match state.senders.get(sender_id).and_then(|s| s.password) {
+        Some(hash) => token.map(|t| state.tokens.verify(t, sender_id, &hash)).unwrap_or(false),
+        None => true
+    }
+}
+
+// Let receivers know about files (or whole shares, when there's no file ID)
+// that can't be downloaded any more:
+fn notify_unavailable(state: &State, sender_id: Id, unavailable: Vec<(Option<Id>, UnavailableReason)>) {
+    for (file_id, reason) in unavailable {
+        let msg = match file_id {
+            Some(file_id) => MsgToReceiver::FileUnavailable { file_id, reason },
+            None => MsgToReceiver::ShareUnavailable { reason }
+        };
+        state.receivers.write().send_if(msg, |r| r.sender_id == sender_id);
+    }
+}
+
+// A 304 carries the validators that the receiver's copy was checked against:
+fn not_modified_response(meta: &Metadata, compression_enabled: bool) -> warp::http::Result<Response<Body>> {
+    let mut res = Response::builder();
+    meta.apply(&mut res, false);
+    if compression_enabled {
+        res.header("vary", "accept-encoding");
+    }
+    res.status(StatusCode::NOT_MODIFIED).body(Body::empty())
+}
+
+fn unavailable_response(reason: UnavailableReason) -> Response<Body> {
+    let msg = match reason {
+        UnavailableReason::Expired => "This file has expired",
+        UnavailableReason::DownloadLimitReached => "This file has been downloaded as many times as allowed"
+    };
+    text_response(StatusCode::GONE, msg)
+}
+
+// warp rejects requests that have no query string at all, so fall back to
+// the default query parameters in that case:
+fn query_or_default<T>() -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone
+    where T: serde::de::DeserializeOwned + Default + Send + 'static
+{
+    warp::query::<T>()
+        .or(warp::any().map(T::default))
+        .unify()
+}
+
+// Every so often, clear out streams that have finished or are going nowhere:
+fn reap_streams(state: State) -> impl Future<Item = (), Error = ()> {
+    let every = Duration::from_secs(state.options.reap_interval);
+    Interval::new(Instant::now() + every, every)
+        .map_err(|e| eprintln!("Stream reaper timer error: {}", e))
+        .for_each(move |_| {
+            let pending_ttl = Duration::from_secs(state.options.stream_pending_ttl);
+            let finished_ttl = Duration::from_secs(state.options.stream_finished_ttl);
+            let segment_ttl = Duration::from_secs(state.options.segment_timeout);
+            let reaped = state.streams.reap(pending_ttl, finished_ttl, segment_ttl) + state.incoming.reap();
+            state.attempts.reap();
+            state.http_limits.reap();
+            state.receiver_download_limits.reap();
+            state.addr_download_limits.reap();
+            state.throttles.reap();
+            for sender_id in state.senders.reap(Duration::from_secs(state.options.sender_grace)) {
+                println!("Sender {} didn't reconnect in time; removing it", sender_id);
+                state.streams.remove_for_sender(sender_id);
+                state.incoming.remove_for_sender(sender_id);
+            }
+            for (sender_id, file_id, reason) in state.senders.expire() {
+                notify_unavailable(&state, sender_id, vec![(file_id, reason)]);
+            }
+            if reaped > 0 {
+                println!("Reaped {} streams", reaped);
+            }
+            Ok(())
+        })
+}
+
 fn with_serialized_sink<InSink, I, E>(tx: InSink) -> impl Sink<SinkItem = I, SinkError = E>
     where
         InSink: Sink<SinkItem = Message, SinkError = E>,
@@ -362,8 +1555,77 @@ fn with_serialized_sink<InSink, I, E>(tx: InSink) -> impl Sink<SinkItem = I, Sin
     })
 }
 
+/// The ways that getting a file from a sender can go wrong, and how
+/// we tell the receiver about it.
+#[derive(Debug, Clone)]
+pub enum DownloadError {
+    /// There is no sender with the ID asked for:
+    SenderNotFound,
+    /// The sender doesn't have the file asked for:
+    FileNotFound,
+    /// The sender can't provide the file right now:
+    Unavailable,
+    /// The sender didn't acknowledge the request, or start uploading, in time:
+    Timeout,
+    /// The receiver already has the latest version of the file:
+    NotModified(Box<FileInfoForStream>),
+    /// None of the range asked for is in the file, which is this big:
+    RangeNotSatisfiable(u64),
+    /// Something else went wrong:
+    Failed(String)
+}
+
+impl DownloadError {
+    fn status(&self) -> StatusCode {
+        match self {
+            DownloadError::SenderNotFound => StatusCode::NOT_FOUND,
+            DownloadError::FileNotFound => StatusCode::NOT_FOUND,
+            DownloadError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            DownloadError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            DownloadError::NotModified(_) => StatusCode::NOT_MODIFIED,
+            DownloadError::RangeNotSatisfiable(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+            DownloadError::Failed(_) => StatusCode::BAD_GATEWAY
+        }
+    }
+    fn response(&self) -> warp::http::Result<Response<Body>> {
+        if let DownloadError::NotModified(info) = self {
+            return not_modified_response(&Metadata::new(info), false)
+        }
+        let mut res = Response::builder();
+        res.status(self.status())
+           .header("content-type", "text/plain; charset=utf-8");
+        if let DownloadError::RangeNotSatisfiable(size) = self {
+            res.header("content-range", format!("bytes */{}", size));
+        }
+        res.body(Body::from(self.to_string()))
+    }
+}
+
+impl From<NackReason> for DownloadError {
+    fn from(reason: NackReason) -> DownloadError {
+        match reason {
+            NackReason::NotFound => DownloadError::FileNotFound,
+            NackReason::Unavailable => DownloadError::Unavailable
+        }
+    }
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DownloadError::SenderNotFound => write!(f, "Sender not found"),
+            DownloadError::FileNotFound => write!(f, "File not found"),
+            DownloadError::Unavailable => write!(f, "File unavailable"),
+            DownloadError::Timeout => write!(f, "Timed out waiting for the sender"),
+            DownloadError::NotModified(_) => write!(f, "Not modified"),
+            DownloadError::RangeNotSatisfiable(size) => write!(f, "Range not satisfiable; the file is {} bytes", size),
+            DownloadError::Failed(msg) => write!(f, "Download failed: {}", msg)
+        }
+    }
+}
+
 #[derive(Display, Debug, Clone)]
-struct Err {
+pub struct Err {
     msg: String
 }
 
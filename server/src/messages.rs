@@ -4,43 +4,104 @@ use crate::id::Id;
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(tag = "type")]
 pub enum MsgToReceiver {
-    /// Acknowledge a handshake message, giving back the ID:
-    HandshakeAck { id: Id },
+    /// Acknowledge a handshake message, giving back the ID and the token needed to reclaim
+    /// it when reconnecting. If the share is password protected, this also comes with a
+    /// token that download URLs need to carry:
+    HandshakeAck { id: Id, reconnect_token: String, token: Option<String> },
+    /// The handshake wasn't accepted, and the receiver isn't connected to the sender:
+    HandshakeRejected { reason: RejectReason },
     /// Notification when files have been added:
     FilesAdded { files: Vec<File> },
     /// Notification when files have been removed:
     FilesRemoved { files: Vec<File> },
     /// A list of files that the sender has:
     FileList { files: Vec<File> },
+    /// A file can't be downloaded any more, because it has expired or
+    /// been downloaded as many times as the sender allowed:
+    FileUnavailable { file_id: Id, reason: UnavailableReason },
+    /// None of the sender's files can be downloaded any more, for the same reasons:
+    ShareUnavailable { reason: UnavailableReason },
+    /// The sender isn't connected, so files can't be downloaded until it is. Receivers
+    /// are told this if the sender drops out, or if they connect before the sender does:
+    SenderOffline,
+    /// The sender has connected. If its share is password protected, this comes with
+    /// a fresh download token, since receivers that were waiting for it won't have one:
+    SenderOnline { token: Option<String> },
+    /// A file queued up with `QueueDownload` can be downloaded now:
+    DownloadReady { file_id: Id },
+    /// We've sent messages or asked for downloads too quickly, so the last one was ignored:
+    RateLimited,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(tag = "type")]
 pub enum MsgFromReceiver {
-    /// Expected when first connected. If client already has ID they provide it:
-    Handshake { sender_id: Id, id: Option<Id> },
+    /// Expected when first connected. If client already has ID they provide it, along
+    /// with the reconnect token they were given for it. The password is needed if the
+    /// sender has protected their share with one:
+    Handshake {
+        sender_id: Id,
+        id: Option<Id>,
+        #[serde(default)] reconnect_token: Option<String>,
+        #[serde(default)] password: Option<String>
+    },
     /// Ask sender to upload a given file to a url defined by stream_id:
     PleaseUpload { file_id: Id, stream_id: Id },
     /// Ask sender to provide the file list for me
     PleaseFileList,
+    /// Let me know when I can download a file. If the sender is offline, this waits
+    /// until it's back; otherwise `DownloadReady` comes straight back:
+    QueueDownload { file_id: Id },
+}
+
+impl MsgFromReceiver {
+    /// A copy of the message that's safe to log:
+    pub fn redacted(&self) -> MsgFromReceiver {
+        match self {
+            MsgFromReceiver::Handshake { sender_id, id, reconnect_token, password } => MsgFromReceiver::Handshake {
+                sender_id: *sender_id,
+                id: *id,
+                reconnect_token: reconnect_token.as_ref().map(|_| "<redacted>".to_owned()),
+                password: password.as_ref().map(|_| "<redacted>".to_owned())
+            },
+            msg => msg.clone()
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(tag = "type")]
 pub enum MsgToSender {
-    /// Acknowledge a handshake message, giving back the ID:
-    HandshakeAck { id: Id },
-    /// Ask sender to upload a given file to a url defined by stream_id:
-    PleaseUpload { file_id: Id, stream_id: Id },
+    /// Acknowledge a handshake message, giving back the ID and the token
+    /// needed to reclaim it when reconnecting:
+    HandshakeAck { id: Id, reconnect_token: String },
+    /// The handshake wasn't accepted:
+    HandshakeRejected { reason: RejectReason },
+    /// Ask sender to upload a given file to a url defined by stream_id. The sender
+    /// should upload `length` bytes starting from `offset`, or everything from `offset`
+    /// to the end of the file if no length is given:
+    PleaseUpload { file_id: Id, stream_id: Id, offset: u64, length: Option<u64> },
     /// Ask sender to provide the file list for me
-    PleaseFileList { receiver_id: Id }
+    PleaseFileList { receiver_id: Id },
+    /// An upload sent over the websocket has finished, with an error if it failed:
+    UploadFinished { stream_id: Id, error: Option<String> },
+    /// A visitor is offering a file to our drop box. The sender can accept it with
+    /// `AcceptIncoming` and then download it from `/api/incoming/{stream_id}`, or
+    /// turn it down with `RejectIncoming`:
+    IncomingFile { stream_id: Id, info: FileInfoForStream }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(tag = "type")]
 pub enum MsgFromSender {
-    /// Expected when first connected. If client already has ID they provide it:
-    Handshake { id: Option<Id> },
+    /// Expected when first connected. If client already has ID they provide it, along
+    /// with the reconnect token they were given for it. A password means that receivers
+    /// will need to know it to see our files:
+    Handshake {
+        id: Option<Id>,
+        #[serde(default)] reconnect_token: Option<String>,
+        #[serde(default)] password: Option<String>
+    },
     /// Notification when files have been added:
     FilesAdded { receiver_id: Option<Id>, files: Vec<File> },
     /// Notification when files have been removed:
@@ -48,7 +109,61 @@ pub enum MsgFromSender {
     /// A list of files that the sender has:
     FileList { receiver_id: Option<Id>, files: Vec<File> },
     /// Info for a file for some active stream. needed for download to begin:
-    PleaseUploadAck { stream_id: Id, info: FileInfoForStream }
+    PleaseUploadAck { stream_id: Id, info: FileInfoForStream },
+    /// The sender can't upload the file asked for in some stream:
+    PleaseUploadNack { stream_id: Id, reason: NackReason },
+    /// Let visitors offer us files, or stop them from doing so:
+    DropBox { enabled: bool },
+    /// We'd like a file that a visitor has offered us:
+    AcceptIncoming { stream_id: Id },
+    /// We don't want a file that a visitor has offered us:
+    RejectIncoming { stream_id: Id, reason: Option<String> },
+    /// Limit how long our share (or one of our files, if a file ID is given) can be
+    /// downloaded for, in seconds from now, and how many times it can be downloaded:
+    SetLimits { file_id: Option<Id>, expires_in: Option<u64>, max_downloads: Option<u32> }
+}
+
+impl MsgFromSender {
+    /// A copy of the message that's safe to log:
+    pub fn redacted(&self) -> MsgFromSender {
+        match self {
+            MsgFromSender::Handshake { id, reconnect_token, password } => MsgFromSender::Handshake {
+                id: *id,
+                reconnect_token: reconnect_token.as_ref().map(|_| "<redacted>".to_owned()),
+                password: password.as_ref().map(|_| "<redacted>".to_owned())
+            },
+            msg => msg.clone()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum NackReason {
+    /// The sender doesn't have a file with the ID asked for:
+    NotFound,
+    /// The sender has the file, but can't upload it right now:
+    Unavailable
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum UnavailableReason {
+    /// The time that the sender allowed has passed:
+    Expired,
+    /// There have been as many downloads as the sender allowed:
+    DownloadLimitReached
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The sender's share is password protected, and no password was given:
+    PasswordRequired,
+    /// The password given was wrong:
+    PasswordIncorrect,
+    /// There have been too many wrong passwords; try again later:
+    TooManyAttempts,
+    /// The ID asked for is in use, and the reconnect token needed to reclaim it
+    /// wasn't given or was wrong:
+    IdInUse
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -56,12 +171,35 @@ pub struct FileInfoForStream {
     /// Name of the file:
     pub name: String,
     /// Size in bytes of the file:
-    pub size: u64
+    pub size: u64,
+    /// When the file was last modified, in milliseconds since the unix epoch:
+    pub modified: Option<u64>,
+    /// A hash of the file contents, if the sender has one. Used as the ETag:
+    pub hash: Option<String>,
+    /// The base64 encoded SHA-256 digest of the file, if the sender has one. We
+    /// check the bytes uploaded against it, and hand it on to receivers:
+    #[serde(default)]
+    pub digest: Option<String>,
+    /// Is the file end-to-end encrypted? If so, the name is encrypted, the
+    /// size is that of the encrypted file, and the server just relays bytes:
+    #[serde(default)]
+    pub encrypted: bool
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct File {
-    id: String,
-    name: String,
-    size: u64
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+    /// When the file was last modified, in milliseconds since the unix epoch. Like
+    /// the hash, this is optional, but declaring it up front lets us answer
+    /// conditional requests without asking for the file:
+    #[serde(default)]
+    pub modified: Option<u64>,
+    /// A hash of the file contents, which should match the one given when it's uploaded:
+    #[serde(default)]
+    pub hash: Option<String>,
+    /// Is the file end-to-end encrypted? If so, the name is encrypted:
+    #[serde(default)]
+    pub encrypted: bool
 }
\ No newline at end of file
@@ -0,0 +1,280 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_derive::Deserialize;
+use warp::http::{HeaderMap, header, response};
+use crate::messages::{File, FileInfoForStream};
+
+/// Should a download be shown in the browser, or saved to disk?
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Disposition {
+    Inline,
+    Attachment
+}
+
+impl Default for Disposition {
+    fn default() -> Disposition {
+        Disposition::Attachment
+    }
+}
+
+/// Build an RFC 6266 Content-Disposition header. Older clients get an ASCII
+/// approximation of the name, and everybody else gets the real thing, encoded
+/// as per RFC 5987.
+pub fn content_disposition(disposition: Disposition, name: &str) -> String {
+    let kind = match disposition {
+        Disposition::Inline => "inline",
+        Disposition::Attachment => "attachment"
+    };
+
+    let fallback: String = name.chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+
+    let mut encoded = String::with_capacity(name.len());
+    for &b in name.as_bytes() {
+        let is_attr_char = b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b);
+        if is_attr_char {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+
+    format!("{}; filename=\"{}\"; filename*=UTF-8''{}", kind, fallback, encoded)
+}
+
+/// The validators we can work out from the info a sender gives us about a file.
+pub struct Metadata {
+    etag: Option<String>,
+    last_modified: Option<SystemTime>
+}
+
+impl Metadata {
+
+    pub fn new(info: &FileInfoForStream) -> Metadata {
+        Metadata::from_parts(info.size, info.modified, info.hash.as_ref())
+    }
+
+    /// The validators for a file in a sender's file list, if they declared any. These
+    /// are the same as we'd get once the file is uploaded, if the sender is consistent.
+    pub fn declared(file: &File) -> Option<Metadata> {
+        if file.modified.is_none() && file.hash.is_none() { return None }
+        Some(Metadata::from_parts(file.size, file.modified, file.hash.as_ref()))
+    }
+
+    fn from_parts(size: u64, modified: Option<u64>, hash: Option<&String>) -> Metadata {
+        let last_modified = modified.map(|ms| UNIX_EPOCH + Duration::from_millis(ms));
+
+        // A content hash makes for a strong ETag. Failing that, size and modification
+        // time are a decent guess at whether a file has changed, but not a promise:
+        let etag = match (hash, modified) {
+            (Some(hash), _) => {
+                let hash: String = hash.chars().filter(|&c| is_etag_char(c)).collect();
+                Some(format!("\"{}\"", hash))
+            },
+            (None, Some(modified)) => Some(format!("W/\"{}-{}\"", size, modified)),
+            (None, None) => None
+        };
+
+        Metadata { etag, last_modified }
+    }
+
+    /// Add ETag and Last-Modified headers to a response. A compressed response
+    /// isn't byte-for-byte the file, so it only gets a weak ETag.
+    pub fn apply(&self, res: &mut response::Builder, compressed: bool) {
+        if let Some(etag) = &self.etag {
+            if compressed && !etag.starts_with("W/") {
+                res.header(header::ETAG, format!("W/{}", etag));
+            } else {
+                res.header(header::ETAG, etag.as_str());
+            }
+        }
+        if let Some(modified) = self.last_modified {
+            res.header(header::LAST_MODIFIED, httpdate::fmt_http_date(modified));
+        }
+    }
+
+    /// Given the request headers, can we respond with 304 Not Modified? If-None-Match
+    /// takes precedence, and If-Modified-Since is only looked at if it's not there.
+    pub fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = header_str(headers, header::IF_NONE_MATCH) {
+            let etag = match &self.etag {
+                Some(etag) => etag,
+                None => return false
+            };
+            return if_none_match.trim() == "*"
+                || if_none_match.split(',').any(|tag| weak_eq(tag.trim(), etag));
+        }
+
+        if let Some(if_modified_since) = header_str(headers, header::IF_MODIFIED_SINCE) {
+            let (since, modified) = match (httpdate::parse_http_date(if_modified_since), self.last_modified) {
+                (Ok(since), Some(modified)) => (since, modified),
+                _ => return false
+            };
+            return unix_secs(modified) <= unix_secs(since);
+        }
+
+        false
+    }
+
+    /// Does the If-Range header match this file? This is true if there is no If-Range
+    /// header. Entity tags must match exactly and be strong, and dates must be exactly
+    /// the Last-Modified date.
+    pub fn if_range_matches(&self, headers: &HeaderMap) -> bool {
+        let if_range = match header_str(headers, header::IF_RANGE) {
+            Some(if_range) => if_range.trim(),
+            None => return true
+        };
+
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            match &self.etag {
+                Some(etag) => !etag.starts_with("W/") && etag == if_range,
+                None => false
+            }
+        } else {
+            match (httpdate::parse_http_date(if_range), self.last_modified) {
+                (Ok(date), Some(modified)) => unix_secs(date) == unix_secs(modified),
+                _ => false
+            }
+        }
+    }
+
+}
+
+// Weak comparison; two tags match if their opaque parts are the same:
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+fn is_etag_char(c: char) -> bool {
+    c == '!' || (c >= '#' && c <= '~')
+}
+
+// HTTP dates only go down to the second:
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|h| h.to_str().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use warp::http::HeaderValue;
+
+    // 2017-07-14T02:40:00Z, in milliseconds:
+    const MODIFIED: u64 = 1_500_000_000_000;
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn date(secs_after_modified: i64) -> String {
+        let secs = (MODIFIED / 1000) as i64 + secs_after_modified;
+        httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs as u64))
+    }
+
+    fn strong() -> Metadata {
+        Metadata::from_parts(10, Some(MODIFIED), Some(&"abc".to_owned()))
+    }
+
+    fn weak() -> Metadata {
+        Metadata::from_parts(10, Some(MODIFIED), None)
+    }
+
+    #[test]
+    fn content_disposition_plain_names() {
+        assert_eq!(
+            content_disposition(Disposition::Attachment, "report.pdf"),
+            "attachment; filename=\"report.pdf\"; filename*=UTF-8''report.pdf"
+        );
+        assert_eq!(
+            content_disposition(Disposition::Inline, "a-b_c.txt"),
+            "inline; filename=\"a-b_c.txt\"; filename*=UTF-8''a-b_c.txt"
+        );
+    }
+
+    #[test]
+    fn content_disposition_escapes_quotes_and_backslashes() {
+        assert_eq!(
+            content_disposition(Disposition::Attachment, "my \"quoted\" file.txt"),
+            "attachment; filename=\"my _quoted_ file.txt\"; filename*=UTF-8''my%20%22quoted%22%20file.txt"
+        );
+        assert_eq!(
+            content_disposition(Disposition::Attachment, "back\\slash\n.txt"),
+            "attachment; filename=\"back_slash_.txt\"; filename*=UTF-8''back%5Cslash%0A.txt"
+        );
+    }
+
+    #[test]
+    fn content_disposition_encodes_non_ascii_names() {
+        assert_eq!(
+            content_disposition(Disposition::Inline, "café.txt"),
+            "inline; filename=\"caf_.txt\"; filename*=UTF-8''caf%C3%A9.txt"
+        );
+        assert_eq!(
+            content_disposition(Disposition::Attachment, "😀"),
+            "attachment; filename=\"_\"; filename*=UTF-8''%F0%9F%98%80"
+        );
+    }
+
+    #[test]
+    fn not_modified_if_none_match() {
+        let meta = strong();
+        assert!(meta.not_modified(&headers(&[(header::IF_NONE_MATCH, "\"abc\"")])));
+        assert!(meta.not_modified(&headers(&[(header::IF_NONE_MATCH, "W/\"abc\"")])));
+        assert!(meta.not_modified(&headers(&[(header::IF_NONE_MATCH, "\"xyz\", \"abc\"")])));
+        assert!(meta.not_modified(&headers(&[(header::IF_NONE_MATCH, "*")])));
+        assert!(!meta.not_modified(&headers(&[(header::IF_NONE_MATCH, "\"xyz\"")])));
+        // Weak tags match weakly too:
+        assert!(weak().not_modified(&headers(&[(header::IF_NONE_MATCH, "W/\"10-1500000000000\"")])));
+        // Without an ETag, nothing matches:
+        let none = Metadata::from_parts(10, None, None);
+        assert!(!none.not_modified(&headers(&[(header::IF_NONE_MATCH, "*")])));
+    }
+
+    #[test]
+    fn not_modified_if_modified_since() {
+        let meta = strong();
+        assert!(meta.not_modified(&headers(&[(header::IF_MODIFIED_SINCE, date(0).as_str())])));
+        assert!(meta.not_modified(&headers(&[(header::IF_MODIFIED_SINCE, date(60).as_str())])));
+        assert!(!meta.not_modified(&headers(&[(header::IF_MODIFIED_SINCE, date(-1).as_str())])));
+        assert!(!meta.not_modified(&headers(&[(header::IF_MODIFIED_SINCE, "yesterday")])));
+        assert!(!meta.not_modified(&headers(&[])));
+        // We can't tell without knowing when the file was modified:
+        let unknown = Metadata::from_parts(10, None, Some(&"abc".to_owned()));
+        assert!(!unknown.not_modified(&headers(&[(header::IF_MODIFIED_SINCE, date(0).as_str())])));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let meta = strong();
+        assert!(!meta.not_modified(&headers(&[
+            (header::IF_NONE_MATCH, "\"xyz\""),
+            (header::IF_MODIFIED_SINCE, date(60).as_str())
+        ])));
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_match() {
+        assert!(strong().if_range_matches(&headers(&[])));
+        assert!(strong().if_range_matches(&headers(&[(header::IF_RANGE, "\"abc\"")])));
+        assert!(!strong().if_range_matches(&headers(&[(header::IF_RANGE, "W/\"abc\"")])));
+        assert!(!strong().if_range_matches(&headers(&[(header::IF_RANGE, "\"xyz\"")])));
+        // Weak ETags never match, even exactly:
+        assert!(!weak().if_range_matches(&headers(&[(header::IF_RANGE, "W/\"10-1500000000000\"")])));
+    }
+
+    #[test]
+    fn if_range_dates_match_exactly() {
+        assert!(weak().if_range_matches(&headers(&[(header::IF_RANGE, date(0).as_str())])));
+        assert!(!weak().if_range_matches(&headers(&[(header::IF_RANGE, date(1).as_str())])));
+        assert!(!weak().if_range_matches(&headers(&[(header::IF_RANGE, date(-1).as_str())])));
+    }
+}
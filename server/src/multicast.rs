@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures::{future, stream, Future, Sink, Stream, sync::mpsc};
+use futures::future::Shared;
+use tokio::timer::Timeout;
+use crate::id::Id;
+use crate::messages::FileInfoForStream;
+use crate::{DownloadError, FileData};
+
+type FileInfoFuture = Box<dyn Future<Item = FileInfoForStream, Error = DownloadError> + Send>;
+// Subscribers are sent an error if the upload fails, which aborts their download:
+type Subscriber = mpsc::Sender<Result<Vec<u8>, ()>>;
+type Subscribers = Arc<Mutex<Option<Vec<Subscriber>>>>;
+type PendingMap = Arc<Mutex<HashMap<(Id, Id), Pending>>>;
+
+/// When several receivers download the same file from a sender at once, we only
+/// ask the sender to upload it once, and hand each chunk on to every receiver.
+///
+/// Receivers can join an upload until the first bytes of it have been sent on.
+/// Anybody arriving after that gets a fresh upload, which others can then join.
+/// Each receiver has a bounded buffer, and the upload goes at the pace of the
+/// slowest receiver, but a receiver that can't take a chunk within `slow_timeout`
+/// is dropped so that it can't hold everybody else up.
+pub struct Multicasts {
+    pending: PendingMap,
+    buffer: usize,
+    slow_timeout: Duration
+}
+
+struct Pending {
+    info: Shared<FileInfoFuture>,
+    // This is None once the upload has started and nobody else can join:
+    subscribers: Subscribers
+}
+
+impl Multicasts {
+    pub fn new(buffer: usize, slow_timeout: Duration) -> Multicasts {
+        Multicasts {
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            buffer,
+            slow_timeout
+        }
+    }
+
+    /// Join an upload of the given file that hasn't started yet, or if there isn't one,
+    /// start a new one by calling `start`. This resolves to the file info once the sender
+    /// has acknowledged the upload, along with a stream of the bytes they upload.
+    pub fn join<F>(&self, sender_id: Id, file_id: Id, start: impl FnOnce() -> F) -> impl Future<Item = (FileInfoForStream, FileData), Error = DownloadError>
+        where F: Future<Item = (FileInfoForStream, FileData), Error = DownloadError> + Send + 'static
+    {
+        let key = (sender_id, file_id);
+        let (tx, rx) = mpsc::channel(self.buffer);
+        let mut pending = self.pending.lock().unwrap();
+
+        if let Some(p) = pending.get(&key) {
+            if let Some(subscribers) = p.subscribers.lock().unwrap().as_mut() {
+                subscribers.push(tx);
+                return future::Either::A(shared_info(&p.info).map(move |info| (info, subscriber_data(rx))));
+            }
+        }
+
+        // There's nothing to join, so start a new upload that others can join:
+        let subscribers: Subscribers = Arc::new(Mutex::new(Some(vec![tx])));
+        let subscribers2 = subscribers.clone();
+        let all_pending = self.pending.clone();
+        let slow_timeout = self.slow_timeout;
+
+        let info: FileInfoFuture = Box::new(start().then(move |res| {
+            match res {
+                Ok((info, data)) => {
+                    tokio::spawn(relay(key, data, subscribers2, all_pending, slow_timeout));
+                    Ok(info)
+                },
+                Err(e) => {
+                    remove_pending(&all_pending, key, &subscribers2);
+                    Err(e)
+                }
+            }
+        }));
+
+        // Drive the upload whether or not anybody is still waiting for it, so that it's
+        // cleaned up if it fails or times out after every receiver has given up on it:
+        let info = info.shared();
+        tokio::spawn(info.clone().then(|_| Ok(())));
+        pending.insert(key, Pending { info: info.clone(), subscribers });
+        future::Either::B(shared_info(&info).map(move |info| (info, subscriber_data(rx))))
+    }
+}
+
+fn shared_info(info: &Shared<FileInfoFuture>) -> impl Future<Item = FileInfoForStream, Error = DownloadError> {
+    info.clone()
+        .map(|info| (*info).clone())
+        .map_err(|e| (*e).clone())
+}
+
+fn subscriber_data(rx: mpsc::Receiver<Result<Vec<u8>, ()>>) -> FileData {
+    Box::new(rx.then(|res| res.and_then(|chunk| chunk)))
+}
+
+// Forward each chunk of an upload on to every receiver that's keeping up. If
+// the upload fails, every receiver is told, and then we stop:
+fn relay(key: (Id, Id), data: FileData, subscribers: Subscribers, pending: PendingMap, slow_timeout: Duration) -> impl Future<Item = (), Error = ()> {
+    data.then(Ok::<_,()>)
+        .into_future()
+        .map_err(|_| ())
+        .and_then(move |(first, rest)| {
+
+            // Once bytes start flowing it's too late for anybody else to join:
+            remove_pending(&pending, key, &subscribers);
+            let subscribers = subscribers.lock().unwrap().take().unwrap_or_else(Vec::new);
+
+            stream::iter_ok(first)
+                .chain(rest)
+                .fold(subscribers, move |subscribers, chunk| {
+                    let failed = chunk.is_err();
+                    let sends: Vec<_> = subscribers.into_iter()
+                        .map(|tx| {
+                            // A receiver that can't keep up is sent an error, which aborts its download
+                            // rather than letting it end early as if complete. A new handle on the
+                            // channel always has room for one message, even when the buffer is full:
+                            let mut abort = tx.clone();
+                            Timeout::new(tx.send(chunk.clone()), slow_timeout)
+                                .then(move |res| {
+                                    if res.is_err() {
+                                        let _ = abort.try_send(Err(()));
+                                    }
+                                    Ok::<_,()>(res.ok())
+                                })
+                        })
+                        .collect();
+                    future::join_all(sends).and_then(|subscribers| {
+                        let subscribers: Vec<Subscriber> = subscribers.into_iter().filter_map(|s| s).collect();
+                        // If nobody is left, stop, which lets the sender know too:
+                        if subscribers.is_empty() || failed { Err(()) } else { Ok(subscribers) }
+                    })
+                })
+                .map(|_| ())
+
+        })
+}
+
+// Remove a pending upload, as long as it's the one we expect and not a newer one:
+fn remove_pending(pending: &Mutex<HashMap<(Id, Id), Pending>>, key: (Id, Id), subscribers: &Subscribers) {
+    let mut pending = pending.lock().unwrap();
+    let is_ours = pending.get(&key)
+        .map(|p| Arc::ptr_eq(&p.subscribers, subscribers))
+        .unwrap_or(false);
+    if is_ours {
+        pending.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use futures::sync::mpsc::UnboundedReceiver;
+    use tokio::runtime::Runtime;
+    use crate::id::IdGen;
+
+    type Upload = future::FutureResult<(FileInfoForStream, FileData), DownloadError>;
+
+    fn info() -> FileInfoForStream {
+        FileInfoForStream {
+            name: "file.txt".to_owned(),
+            size: 5,
+            modified: None,
+            hash: None,
+            digest: None,
+            encrypted: false
+        }
+    }
+
+    // Start an upload whose bytes come from `rx`, counting how many have been started:
+    fn upload(starts: &Arc<AtomicUsize>, rx: UnboundedReceiver<Vec<u8>>) -> impl FnOnce() -> Upload {
+        let starts = starts.clone();
+        move || {
+            starts.fetch_add(1, Ordering::SeqCst);
+            future::ok((info(), Box::new(rx) as FileData))
+        }
+    }
+
+    fn join(rt: &mut Runtime, multicasts: &Arc<Multicasts>, key: (Id, Id), start: impl FnOnce() -> Upload + Send + 'static) -> FileData {
+        let multicasts = multicasts.clone();
+        let (_, data) = rt.block_on(future::lazy(move || multicasts.join(key.0, key.1, start))).unwrap();
+        data
+    }
+
+    // Things happen on the runtime's threads, so give them a moment:
+    fn eventually(mut done: impl FnMut() -> bool) -> bool {
+        for _ in 0..200 {
+            if done() { return true }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    fn key() -> (Id, Id) {
+        let mut ids = IdGen::new();
+        (ids.make_id(), ids.make_id())
+    }
+
+    #[test]
+    fn late_joiners_get_a_fresh_upload() {
+        let mut rt = Runtime::new().unwrap();
+        let multicasts = Arc::new(Multicasts::new(4, Duration::from_secs(5)));
+        let starts = Arc::new(AtomicUsize::new(0));
+        let key = key();
+
+        let (tx, rx) = mpsc::unbounded();
+        let first = join(&mut rt, &multicasts, key, upload(&starts, rx));
+        let (_unused_tx, unused_rx) = mpsc::unbounded();
+        let second = join(&mut rt, &multicasts, key, upload(&starts, unused_rx));
+        assert_eq!(starts.load(Ordering::SeqCst), 1);
+
+        // Once bytes are flowing, it's too late to join:
+        tx.unbounded_send(b"hello".to_vec()).unwrap();
+        drop(tx);
+        assert_eq!(first.collect().wait().unwrap(), vec![b"hello".to_vec()]);
+        assert_eq!(second.collect().wait().unwrap(), vec![b"hello".to_vec()]);
+
+        let (tx, rx) = mpsc::unbounded();
+        let third = join(&mut rt, &multicasts, key, upload(&starts, rx));
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+        tx.unbounded_send(b"again".to_vec()).unwrap();
+        drop(tx);
+        assert_eq!(third.collect().wait().unwrap(), vec![b"again".to_vec()]);
+    }
+
+    #[test]
+    fn slow_receivers_are_dropped() {
+        let mut rt = Runtime::new().unwrap();
+        let multicasts = Arc::new(Multicasts::new(1, Duration::from_millis(100)));
+        let starts = Arc::new(AtomicUsize::new(0));
+        let key = key();
+
+        let (tx, rx) = mpsc::unbounded();
+        let fast = join(&mut rt, &multicasts, key, upload(&starts, rx));
+        let slow = join(&mut rt, &multicasts, key, upload(&starts, mpsc::unbounded().1));
+
+        let chunks: Vec<Vec<u8>> = (0..10u8).map(|n| vec![n]).collect();
+        for chunk in &chunks {
+            tx.unbounded_send(chunk.clone()).unwrap();
+        }
+        drop(tx);
+
+        // The fast receiver gets everything, despite the slow one never reading anything:
+        assert_eq!(fast.collect().wait().unwrap(), chunks);
+        // The slow one was dropped once its buffer was full, and its download fails:
+        assert_eq!(slow.collect().wait(), Err(()));
+    }
+
+    #[test]
+    fn upload_stops_when_everybody_leaves() {
+        let mut rt = Runtime::new().unwrap();
+        let multicasts = Arc::new(Multicasts::new(4, Duration::from_secs(5)));
+        let starts = Arc::new(AtomicUsize::new(0));
+        let key = key();
+
+        let (tx, rx) = mpsc::unbounded();
+        let first = join(&mut rt, &multicasts, key, upload(&starts, rx));
+        let second = join(&mut rt, &multicasts, key, upload(&starts, mpsc::unbounded().1));
+        drop(first);
+        drop(second);
+
+        // The next chunk finds nobody to send to, so the upload is dropped:
+        tx.unbounded_send(b"anyone?".to_vec()).unwrap();
+        assert!(eventually(|| tx.unbounded_send(b"hello?".to_vec()).is_err()));
+    }
+
+    #[test]
+    fn failed_uploads_are_forgotten() {
+        let mut rt = Runtime::new().unwrap();
+        let multicasts = Arc::new(Multicasts::new(4, Duration::from_secs(5)));
+        let key = key();
+
+        // Nobody waits for this upload, but it's still cleaned up when it fails:
+        let m = multicasts.clone();
+        rt.block_on(future::lazy(move || {
+            let _ = m.join(key.0, key.1, || future::err::<(FileInfoForStream, FileData), _>(DownloadError::Timeout));
+            Ok::<_, ()>(())
+        })).unwrap();
+        assert!(eventually(|| multicasts.pending.lock().unwrap().is_empty()));
+
+        // So the next receiver starts a new one:
+        let starts = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = mpsc::unbounded();
+        let data = join(&mut rt, &multicasts, key, upload(&starts, rx));
+        assert_eq!(starts.load(Ordering::SeqCst), 1);
+        drop(tx);
+        assert!(data.collect().wait().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+/// A single range of bytes asked for via an HTTP `Range` header. Like
+/// in HTTP, `end` is inclusive. If it's not given, the range runs to
+/// the end of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: Option<u64>
+}
+
+impl ByteRange {
+
+    /// Parse a range header like "bytes=100-" or "bytes=100-199". We don't
+    /// support multiple ranges or suffix ranges ("bytes=-500"), and hand back
+    /// None for those (or anything invalid). RFC 7233 lets us ignore the header
+    /// in that case and serve the whole file instead. Suffix ranges would need
+    /// the size of the file, which we don't know until after we've told the
+    /// sender where to start uploading from.
+    pub fn parse(header: &str) -> Option<ByteRange> {
+        let header = header.trim();
+        if !header.starts_with("bytes=") { return None }
+
+        let spec = header["bytes=".len()..].trim();
+        if spec.contains(',') { return None }
+
+        let mut parts = spec.splitn(2, '-');
+        let start = parts.next()?.trim();
+        let end = parts.next()?.trim();
+
+        let start: u64 = start.parse().ok()?;
+        let end: Option<u64> = if end.is_empty() {
+            None
+        } else {
+            Some(end.parse().ok()?)
+        };
+
+        let range = ByteRange { start, end };
+        match end {
+            Some(end) if end < start => None,
+            // The length of a range covering every possible byte doesn't fit in a u64:
+            Some(_) if range.length().is_none() => None,
+            _ => Some(range)
+        }
+    }
+
+    /// How many bytes have been asked for, if we know:
+    pub fn length(&self) -> Option<u64> {
+        self.end.and_then(|end| end.checked_sub(self.start)?.checked_add(1))
+    }
+
+    /// Clamp the range to a file of the given size, handing back the inclusive
+    /// start and end byte, or None if the range can't be satisfied at all.
+    pub fn resolve(&self, size: u64) -> Option<(u64, u64)> {
+        if self.start >= size { return None }
+        let last = size - 1;
+        let end = self.end.map(|end| end.min(last)).unwrap_or(last);
+        Some((self.start, end))
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn range(start: u64, end: Option<u64>) -> Option<ByteRange> {
+        Some(ByteRange { start, end })
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(ByteRange::parse("bytes=0-"), range(0, None));
+        assert_eq!(ByteRange::parse("bytes=5-9"), range(5, Some(9)));
+        assert_eq!(ByteRange::parse("bytes=5-5"), range(5, Some(5)));
+        assert_eq!(ByteRange::parse(" bytes= 100 - 199 "), range(100, Some(199)));
+    }
+
+    #[test]
+    fn ignores_what_we_dont_support() {
+        assert_eq!(ByteRange::parse("bytes=-500"), None);
+        assert_eq!(ByteRange::parse("bytes=0-4,10-14"), None);
+        assert_eq!(ByteRange::parse("bytes=0-4, 10-"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_ranges() {
+        assert_eq!(ByteRange::parse("bytes=9-5"), None);
+        assert_eq!(ByteRange::parse(""), None);
+        assert_eq!(ByteRange::parse("bytes="), None);
+        assert_eq!(ByteRange::parse("bytes=-"), None);
+        assert_eq!(ByteRange::parse("bytes=5"), None);
+        assert_eq!(ByteRange::parse("bytes=a-b"), None);
+        assert_eq!(ByteRange::parse("bytes=5-b"), None);
+        assert_eq!(ByteRange::parse("bytes=-5-9"), None);
+        assert_eq!(ByteRange::parse("items=0-5"), None);
+        assert_eq!(ByteRange::parse("0-5"), None);
+        assert_eq!(ByteRange::parse("bytes=99999999999999999999-"), None);
+        // Too long for its length to fit in a u64:
+        assert_eq!(ByteRange::parse("bytes=0-18446744073709551615"), None);
+    }
+
+    #[test]
+    fn knows_its_length() {
+        assert_eq!(ByteRange::parse("bytes=5-9").unwrap().length(), Some(5));
+        assert_eq!(ByteRange::parse("bytes=0-").unwrap().length(), None);
+        assert_eq!(ByteRange::parse("bytes=1-18446744073709551615").unwrap().length(), Some(u64::max_value()));
+        assert_eq!(ByteRange { start: 0, end: Some(u64::max_value()) }.length(), None);
+    }
+
+    #[test]
+    fn resolves_against_file_size() {
+        let open = ByteRange::parse("bytes=0-").unwrap();
+        assert_eq!(open.resolve(10), Some((0, 9)));
+        assert_eq!(open.resolve(1), Some((0, 0)));
+        assert_eq!(open.resolve(0), None);
+
+        let middle = ByteRange::parse("bytes=5-9").unwrap();
+        assert_eq!(middle.resolve(20), Some((5, 9)));
+        // The end is clamped to the file:
+        assert_eq!(middle.resolve(7), Some((5, 6)));
+        assert_eq!(middle.resolve(6), Some((5, 5)));
+        // But a start at or past the end can't be satisfied:
+        assert_eq!(middle.resolve(5), None);
+        assert_eq!(middle.resolve(2), None);
+    }
+}
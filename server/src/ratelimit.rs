@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// A token bucket: it holds up to `burst` tokens, and is topped up at `rate`
+/// tokens per second. Each request takes a token, and is turned away if there
+/// are none left. A rate of 0 means that there's no limit.
+pub struct Bucket {
+    tokens: f64,
+    rate: f64,
+    burst: f64,
+    last: Instant
+}
+
+impl Bucket {
+    pub fn new(rate: f64, burst: u32) -> Bucket {
+        Bucket {
+            tokens: burst as f64,
+            rate,
+            burst: burst as f64,
+            last: Instant::now()
+        }
+    }
+    pub fn take(&mut self) -> bool {
+        if self.rate <= 0.0 { return true }
+        self.refill();
+        if self.tokens < 1.0 { return false }
+        self.tokens -= 1.0;
+        true
+    }
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.burst
+    }
+}
+
+/// A token bucket for each of some set of keys, like addresses or receivers.
+pub struct Limiter<K> {
+    buckets: Mutex<HashMap<K, Bucket>>,
+    rate: f64,
+    burst: u32
+}
+
+impl <K: Hash + Eq> Limiter<K> {
+    pub fn new(rate: f64, burst: u32) -> Limiter<K> {
+        Limiter {
+            buckets: Mutex::new(HashMap::new()),
+            rate,
+            burst
+        }
+    }
+    /// Can this key make another request right now? Counts the request if so.
+    pub fn allow(&self, key: K) -> bool {
+        if self.rate <= 0.0 { return true }
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.lock().unwrap()
+            .entry(key)
+            .or_insert_with(|| Bucket::new(rate, burst))
+            .take()
+    }
+    /// Forget about keys that have been quiet long enough for their bucket to fill
+    /// back up, since a new bucket would be just the same:
+    pub fn reap(&self) {
+        self.buckets.lock().unwrap().retain(|_, b| !b.is_full());
+    }
+}
+
+/// Rejection for HTTP requests that go over the limit, which becomes a 429:
+#[derive(Debug)]
+pub struct RateLimited;
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Too many requests")
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn bucket_allows_a_burst() {
+        let mut bucket = Bucket::new(1.0, 3);
+        assert!(bucket.take());
+        assert!(bucket.take());
+        assert!(bucket.take());
+        assert!(!bucket.take());
+    }
+
+    #[test]
+    fn bucket_refills() {
+        let mut bucket = Bucket::new(20.0, 2);
+        assert!(bucket.take());
+        assert!(bucket.take());
+        assert!(!bucket.take());
+        // A token comes back every 50ms:
+        thread::sleep(Duration::from_millis(75));
+        assert!(bucket.take());
+        assert!(!bucket.take());
+        // But no more than the burst builds up:
+        thread::sleep(Duration::from_millis(250));
+        assert!(bucket.is_full());
+        assert!(bucket.take());
+        assert!(bucket.take());
+        assert!(!bucket.take());
+    }
+
+    #[test]
+    fn no_rate_means_no_limit() {
+        let mut bucket = Bucket::new(0.0, 0);
+        for _ in 0..100 {
+            assert!(bucket.take());
+        }
+        let limiter = Limiter::new(0.0, 0);
+        for _ in 0..100 {
+            assert!(limiter.allow(1));
+        }
+    }
+
+    #[test]
+    fn limiter_keeps_keys_apart() {
+        let limiter = Limiter::new(1.0, 1);
+        assert!(limiter.allow("a"));
+        assert!(!limiter.allow("a"));
+        assert!(limiter.allow("b"));
+    }
+
+    #[test]
+    fn limiter_forgets_full_buckets() {
+        let limiter = Limiter::new(20.0, 1);
+        assert!(limiter.allow("a"));
+        limiter.reap();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+        thread::sleep(Duration::from_millis(75));
+        limiter.reap();
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+}
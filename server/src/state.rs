@@ -1,8 +1,18 @@
-use std::collections::HashMap;
+use std::collections::{HashMap,HashSet};
+use std::net::{IpAddr,SocketAddr};
+use std::time::{Duration,Instant};
 use std::sync::{RwLockWriteGuard,Mutex,RwLock};
 use futures::sync::{oneshot,mpsc};
 use crate::id::{IdGen,Id};
-use crate::messages::{MsgToSender,MsgToReceiver,FileInfoForStream};
+use crate::messages::{MsgToSender,MsgToReceiver,FileInfoForStream,NackReason,File,UnavailableReason,RejectReason};
+use crate::cli::Options;
+use crate::multicast::Multicasts;
+use crate::range::ByteRange;
+use crate::digest::{Sha256Digest,Verifier};
+use crate::{Err, FileData};
+use crate::auth::{self,PasswordHash,Tokens,Attempts};
+use crate::ratelimit::Limiter;
+use crate::throttle::Throttles;
 
 pub type Tx<Msg> = mpsc::Sender<Msg>;
 pub type UnboundedTx<Msg> = mpsc::UnboundedSender<Msg>;
@@ -23,23 +33,160 @@ impl Senders {
     fn get_id(&self) -> Id {
         self.id_gen.lock().unwrap().make_id()
     }
-    pub fn add(&self, sender_tx: UnboundedTx<MsgToSender>, id: Option<Id>) -> Id {
+    /// Add a sender, handing back its ID and a new reconnect token. A sender can reclaim an
+    /// ID that's in use by giving the reconnect token it was last given for it, but nobody
+    /// else can. IDs that aren't in use can be claimed by anybody. A sender that reclaims
+    /// its ID keeps its share as it was, and is sent any messages that were held for it while
+    /// it was suspended. That's so even if we haven't noticed its old connection go yet, so
+    /// that reconnecting can't be used to reset its limits and download counts.
+    pub fn add(&self, sender_tx: UnboundedTx<MsgToSender>, id: Option<Id>, token: Option<&str>, password: Option<PasswordHash>) -> Result<(Id, String), RejectReason> {
+        let mut senders = self.senders.write().unwrap();
         let this_id = id.unwrap_or_else(|| self.get_id());
-        self.senders.write().unwrap().insert(this_id, Sender { tx: sender_tx });
-        this_id
+        let reconnect_token = auth::reconnect_token();
+        if let Some(existing) = senders.get_mut(&this_id) {
+            if !auth::reconnect_token_matches(token, &existing.reconnect_token) {
+                return Err(RejectReason::IdInUse)
+            }
+            for msg in existing.held.drain(..) {
+                let _ = sender_tx.unbounded_send(msg);
+            }
+            existing.tx = sender_tx;
+            existing.reconnect_token = reconnect_token.clone();
+            existing.password = password;
+            existing.suspended = None;
+            return Ok((this_id, reconnect_token))
+        }
+        senders.insert(this_id, Sender {
+            tx: sender_tx,
+            reconnect_token: reconnect_token.clone(),
+            suspended: None,
+            held: Vec::new(),
+            files: Vec::new(),
+            drop_box: false,
+            password,
+            limits: Limits::default(),
+            file_limits: HashMap::new(),
+            counted: HashSet::new()
+        });
+        Ok((this_id, reconnect_token))
     }
-    pub fn remove(&self, sender_id: Id) -> bool {
-        self.senders.write().unwrap()
-            .remove(&sender_id)
-            .map(|_| true)
-            .unwrap_or(false)
+    /// A sender has disconnected. Rather than removing it straight away, we keep its share
+    /// around for a while in case it reconnects, holding on to any messages for it until then,
+    /// starting with `held`. This does nothing if a newer connection has reclaimed the ID since
+    /// we gave out this reconnect token, and returns true otherwise.
+    pub fn suspend(&self, sender_id: Id, token: &str, held: Vec<MsgToSender>) -> bool {
+        match self.senders.write().unwrap().get_mut(&sender_id) {
+            Some(sender) if sender.reconnect_token == token => {
+                sender.suspended = Some(Instant::now());
+                for msg in held {
+                    sender.hold(msg);
+                }
+                true
+            },
+            _ => false
+        }
+    }
+    /// The password hash of a sender that this reconnect token lets us reclaim, if there is one.
+    /// A sender that reconnects with the same password keeps the same hash, so that the download
+    /// tokens handed out for it stay valid.
+    pub fn reclaimable_password(&self, sender_id: Id, token: Option<&str>) -> Option<PasswordHash> {
+        self.senders.read().unwrap().get(&sender_id)
+            .filter(|s| auth::reconnect_token_matches(token, &s.reconnect_token))
+            .and_then(|s| s.password.clone())
+    }
+    /// Remove senders that have been suspended for longer than `grace`, handing back their IDs.
+    pub fn reap(&self, grace: Duration) -> Vec<Id> {
+        let mut senders = self.senders.write().unwrap();
+        let expired: Vec<Id> = senders.iter()
+            .filter(|(_, s)| s.suspended.map(|since| since.elapsed() >= grace).unwrap_or(false))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in &expired {
+            senders.remove(id);
+        }
+        expired
     }
     pub fn get(&self, sender_id: Id) -> Option<Sender> {
         self.senders.read().unwrap().get(&sender_id).map(|s| s.clone())
     }
+    pub fn update_files(&self, sender_id: Id, f: impl FnOnce(&mut Vec<File>)) {
+        if let Some(sender) = self.senders.write().unwrap().get_mut(&sender_id) {
+            f(&mut sender.files);
+        }
+    }
+    pub fn set_drop_box(&self, sender_id: Id, enabled: bool) {
+        if let Some(sender) = self.senders.write().unwrap().get_mut(&sender_id) {
+            sender.drop_box = enabled;
+        }
+    }
+    /// Limit how long the share, or one file in it, is available for and how many times
+    /// it can be downloaded. This replaces any previous limits, and the count of downloads.
+    pub fn set_limits(&self, sender_id: Id, file_id: Option<Id>, limits: Limits) {
+        if let Some(sender) = self.senders.write().unwrap().get_mut(&sender_id) {
+            match file_id {
+                Some(file_id) => { sender.file_limits.insert(file_id, limits); },
+                None => { sender.limits = limits; }
+            }
+        }
+    }
+    /// Does a download that starts part way through a file (`resume`) carry on from one that
+    /// this address has already had counted, rather than being a new download of the file?
+    pub fn is_resume(&self, sender_id: Id, file_id: Id, addr: Option<IpAddr>, resume: bool) -> bool {
+        self.senders.read().unwrap().get(&sender_id)
+            .map(|sender| sender.is_resume(file_id, addr, resume))
+            .unwrap_or(false)
+    }
+    /// Check that a file can be downloaded, without counting a download, so that we don't
+    /// bother the sender for a file that it wouldn't let us have anyway. `resume` is true if
+    /// the download starts part way through the file; see `start_download`.
+    pub fn check_download(&self, sender_id: Id, file_id: Id, addr: Option<IpAddr>, resume: bool) -> Result<(), UnavailableReason> {
+        match self.senders.read().unwrap().get(&sender_id) {
+            Some(sender) => sender.check_download(file_id, sender.is_resume(file_id, addr, resume)),
+            None => Ok(())
+        }
+    }
+    /// Check that a file can be downloaded, and count the download if so. This happens once
+    /// the bytes start flowing, so requests that don't end up with the file don't count. A
+    /// download that starts part way through the file (`resume`) doesn't count as another one
+    /// if this address has already had a counted download of the file, so it's allowed past
+    /// the download limit; otherwise, it's counted like any other. Hands back anything that
+    /// has now run out of downloads, so that receivers can be told.
+    pub fn start_download(&self, sender_id: Id, file_id: Id, addr: Option<IpAddr>, resume: bool) -> Result<Vec<(Option<Id>, UnavailableReason)>, UnavailableReason> {
+        let mut senders = self.senders.write().unwrap();
+        let sender = match senders.get_mut(&sender_id) {
+            Some(sender) => sender,
+            None => return Ok(Vec::new())
+        };
+        let resume = sender.is_resume(file_id, addr, resume);
+        sender.check_download(file_id, resume)?;
+        if !resume {
+            sender.limits.downloads += 1;
+            if let Some(limits) = sender.file_limits.get_mut(&file_id) {
+                limits.downloads += 1;
+            }
+            sender.counted.insert((file_id, addr));
+        }
+        Ok(sender.newly_unavailable())
+    }
+    /// Find shares and files that have just expired, so that receivers can be told.
+    pub fn expire(&self) -> Vec<(Id, Option<Id>, UnavailableReason)> {
+        let mut senders = self.senders.write().unwrap();
+        let mut expired = Vec::new();
+        for (&sender_id, sender) in senders.iter_mut() {
+            for (file_id, reason) in sender.newly_unavailable() {
+                expired.push((sender_id, file_id, reason));
+            }
+        }
+        expired
+    }
+    /// Send a message to a sender, or hold on to it until the sender reconnects if it's suspended.
     pub fn send(&self, sender_id: Id, msg: MsgToSender) -> bool {
-        if let Some(sender) = self.senders.write().unwrap().get(&sender_id) {
-            let _ = sender.tx.unbounded_send(msg);
+        if let Some(sender) = self.senders.write().unwrap().get_mut(&sender_id) {
+            if sender.suspended.is_some() {
+                sender.hold(msg);
+            } else {
+                let _ = sender.tx.unbounded_send(msg);
+            }
             return true;
         }
         false
@@ -49,6 +196,98 @@ impl Senders {
 #[derive(Clone)]
 pub struct Sender {
     pub tx: UnboundedTx<MsgToSender>,
+    /// Needed to reclaim this sender's ID:
+    reconnect_token: String,
+    /// When the sender disconnected, if it has and hasn't reconnected yet:
+    pub suspended: Option<Instant>,
+    /// Messages waiting for the sender to reconnect:
+    held: Vec<MsgToSender>,
+    /// The files that this sender has told us about:
+    pub files: Vec<File>,
+    /// Can visitors offer files to this sender?
+    pub drop_box: bool,
+    /// If set, receivers need to know the password to see our files:
+    pub password: Option<PasswordHash>,
+    /// Limits on the whole share, and on individual files:
+    pub limits: Limits,
+    pub file_limits: HashMap<Id, Limits>,
+    /// The files that each address has had a counted download of, which it can resume:
+    counted: HashSet<(Id, Option<IpAddr>)>
+}
+
+impl Sender {
+    fn hold(&mut self, msg: MsgToSender) {
+        if !self.held.contains(&msg) {
+            self.held.push(msg);
+        }
+    }
+    fn is_resume(&self, file_id: Id, addr: Option<IpAddr>, resume: bool) -> bool {
+        resume && self.counted.contains(&(file_id, addr))
+    }
+    fn check_download(&self, file_id: Id, resume: bool) -> Result<(), UnavailableReason> {
+        self.limits.check(!resume)?;
+        match self.file_limits.get(&file_id) {
+            Some(limits) => limits.check(!resume),
+            None => Ok(())
+        }
+    }
+    // Anything that's run out since we last looked, with None standing for the whole share:
+    fn newly_unavailable(&mut self) -> Vec<(Option<Id>, UnavailableReason)> {
+        let mut unavailable = Vec::new();
+        if let Some(reason) = self.limits.newly_unavailable() {
+            unavailable.push((None, reason));
+        }
+        for (&file_id, limits) in self.file_limits.iter_mut() {
+            if let Some(reason) = limits.newly_unavailable() {
+                unavailable.push((Some(file_id), reason));
+            }
+        }
+        unavailable
+    }
+}
+
+/// How long a share or file can be downloaded for, and how many times.
+#[derive(Clone, Default)]
+pub struct Limits {
+    expires: Option<Instant>,
+    max_downloads: Option<u32>,
+    downloads: u32,
+    // Have receivers been told that this has run out?
+    notified: bool
+}
+
+impl Limits {
+    pub fn new(expires_in: Option<Duration>, max_downloads: Option<u32>) -> Limits {
+        Limits {
+            expires: expires_in.map(|d| Instant::now() + d),
+            max_downloads,
+            downloads: 0,
+            notified: false
+        }
+    }
+    // Resumed downloads don't count, so are allowed until expiry:
+    fn check(&self, counts: bool) -> Result<(), UnavailableReason> {
+        match self.unavailable() {
+            Some(UnavailableReason::DownloadLimitReached) if !counts => Ok(()),
+            Some(reason) => Err(reason),
+            None => Ok(())
+        }
+    }
+    fn unavailable(&self) -> Option<UnavailableReason> {
+        if self.expires.map(|e| Instant::now() >= e).unwrap_or(false) {
+            Some(UnavailableReason::Expired)
+        } else if self.max_downloads.map(|max| self.downloads >= max).unwrap_or(false) {
+            Some(UnavailableReason::DownloadLimitReached)
+        } else {
+            None
+        }
+    }
+    fn newly_unavailable(&mut self) -> Option<UnavailableReason> {
+        if self.notified { return None }
+        let reason = self.unavailable()?;
+        self.notified = true;
+        Some(reason)
+    }
 }
 
 /// Receivers connect to senders and ask for files
@@ -67,10 +306,28 @@ impl Receivers {
     fn get_id(&self) -> Id {
         self.id_gen.lock().unwrap().make_id()
     }
-    pub fn add(&self, sender_id: Id, receiver_tx: UnboundedTx<MsgToReceiver>, receiver_id: Option<Id>) -> Id {
+    /// Add a receiver, handing back its ID and a new reconnect token. Like senders, a
+    /// receiver ID that's in use can only be reclaimed with its last reconnect token.
+    /// Receivers can connect before their sender does, in which case they're `waiting`.
+    /// `password` is the hash that the receiver's password was checked against, if any.
+    pub fn add(&self, sender_id: Id, receiver_tx: UnboundedTx<MsgToReceiver>, receiver_id: Option<Id>, token: Option<&str>, waiting: Option<Waiting>, password: Option<PasswordHash>) -> Result<(Id, String), RejectReason> {
+        let mut receivers = self.receivers.write().unwrap();
         let this_id = receiver_id.unwrap_or_else(|| self.get_id());
-        self.receivers.write().unwrap().insert(this_id, Receiver { tx: receiver_tx, sender_id });
-        this_id
+        if let Some(existing) = receivers.get(&this_id) {
+            if !auth::reconnect_token_matches(token, &existing.reconnect_token) {
+                return Err(RejectReason::IdInUse)
+            }
+        }
+        let reconnect_token = auth::reconnect_token();
+        receivers.insert(this_id, Receiver {
+            tx: receiver_tx,
+            sender_id,
+            reconnect_token: reconnect_token.clone(),
+            waiting,
+            password,
+            queued: Vec::new()
+        });
+        Ok((this_id, reconnect_token))
     }
     pub fn get(&self, receiver_id: Id) -> Option<Receiver> {
         self.receivers.read().unwrap().get(&receiver_id).map(|s| s.clone())
@@ -86,7 +343,24 @@ impl Receivers {
 #[derive(Clone)]
 pub struct Receiver {
     pub tx: UnboundedTx<MsgToReceiver>,
-    pub sender_id: Id
+    pub sender_id: Id,
+    /// Needed to reclaim this receiver's ID:
+    reconnect_token: String,
+    /// Connected before the sender did, so the password it gave hasn't been checked yet:
+    waiting: Option<Waiting>,
+    /// The password hash that this receiver has proved it knows the password for, if any.
+    /// If the sender's password changes, the receiver has to prove it knows the new one:
+    pub password: Option<PasswordHash>,
+    /// Files to download once the sender is online:
+    queued: Vec<Id>
+}
+
+/// What a receiver that connected before its sender gave us, so that we can
+/// check its password once the sender arrives and we know what it should be.
+#[derive(Clone)]
+pub struct Waiting {
+    pub addr: Option<SocketAddr>,
+    pub password: Option<String>
 }
 
 pub struct ReceiversWriteLock<'a> {
@@ -107,8 +381,62 @@ impl <'a> ReceiversWriteLock<'a> {
             }
         }
     }
-    pub fn remove(&mut self, receiver_id: Id) -> bool {
-        self.lock.remove(&receiver_id).map(|_| true).unwrap_or(false)
+    /// Remember that a receiver wants to download a file once its sender is online.
+    pub fn queue_download(&mut self, receiver_id: Id, file_id: Id) {
+        if let Some(r) = self.lock.get_mut(&receiver_id) {
+            if !r.queued.contains(&file_id) {
+                r.queued.push(file_id);
+            }
+        }
+    }
+    /// The receivers that connected before this sender did, so that their passwords can be checked.
+    pub fn waiting_for(&self, sender_id: Id) -> Vec<(Id, Waiting)> {
+        self.lock.iter()
+            .filter(|(_, r)| r.sender_id == sender_id)
+            .filter_map(|(&receiver_id, r)| r.waiting.clone().map(|w| (receiver_id, w)))
+            .collect()
+    }
+    /// A sender has come online with the given password. `checked` holds the outcome of
+    /// checking the passwords of receivers that were waiting for it. Only receivers that have
+    /// proved they know this password are let in; the rest, including any that knew an older
+    /// password, are turned away and need to handshake again. Hands back the IDs of the
+    /// receivers let in, along with the files that each queued up to download while the
+    /// sender was offline.
+    pub fn sender_online(&mut self, sender_id: Id, password: Option<&PasswordHash>, checked: &[(Id, Result<Option<PasswordHash>, RejectReason>)]) -> Vec<(Id, Vec<Id>)> {
+        let mut online = Vec::new();
+        self.lock.retain(|&receiver_id, r| {
+            if r.sender_id != sender_id { return true }
+            let admitted = match checked.iter().find(|(id, _)| *id == receiver_id) {
+                Some((_, checked)) => checked.clone(),
+                None => Ok(r.password.clone())
+            };
+            let allowed = match (password, admitted) {
+                (None, _) => Ok(None),
+                (Some(_), Err(reason)) => Err(reason),
+                (Some(current), Ok(Some(ref hash))) if hash == current => Ok(Some(hash.clone())),
+                (Some(_), Ok(Some(_))) => Err(RejectReason::PasswordIncorrect),
+                (Some(_), Ok(None)) => Err(RejectReason::PasswordRequired)
+            };
+            match allowed {
+                Ok(hash) => r.password = hash,
+                Err(reason) => {
+                    let _ = r.tx.unbounded_send(MsgToReceiver::HandshakeRejected { reason });
+                    return false
+                }
+            }
+            r.waiting = None;
+            online.push((receiver_id, std::mem::replace(&mut r.queued, Vec::new())));
+            true
+        });
+        online
+    }
+    /// Remove a receiver, as long as it hasn't been reclaimed by a newer connection
+    /// since we gave it this reconnect token:
+    pub fn remove(&mut self, receiver_id: Id, token: &str) -> bool {
+        if self.lock.get(&receiver_id).map(|r| r.reconnect_token != token).unwrap_or(true) {
+            return false
+        }
+        self.lock.remove(&receiver_id).is_some()
     }
 }
 
@@ -130,9 +458,20 @@ impl Streams {
     fn get_id(&self) -> Id {
         self.id_gen.lock().unwrap().make_id()
     }
-    pub fn add(&self, stream_data: Tx<Vec<u8>>, stream_info: oneshot::Sender<FileInfoForStream>) -> Id {
+    pub fn add(&self, sender_id: Id, file_id: Option<Id>, range: Option<ByteRange>, stream_data: StreamData, stream_info: StreamInfo) -> Id {
         let stream_id = self.get_id();
+        let now = Instant::now();
         self.streams.lock().unwrap().insert(stream_id, Stream {
+            sender_id: sender_id,
+            file_id: file_id,
+            range: range,
+            state: StreamState::AwaitingAck,
+            created: now,
+            updated: now,
+            received: 0,
+            expected: None,
+            verifier: None,
+            segment_in_progress: false,
             info: Some(stream_info),
             data: Some(stream_data)
         });
@@ -140,41 +479,528 @@ impl Streams {
     }
     pub fn take_info(&self, stream_id: Id) -> Option<StreamInfo> {
         match self.streams.lock().unwrap().get_mut(&stream_id) {
-            Some(s) => std::mem::replace(&mut s.info, None),
+            Some(s) => {
+                let info = std::mem::replace(&mut s.info, None);
+                if info.is_some() { s.set_state(StreamState::AwaitingUpload) }
+                info
+            },
             None => None
         }
     }
+    /// The sender has told us about the file for this stream, so we now know how
+    /// many bytes to expect from them and, if they gave us a digest, what those bytes
+    /// should hash to. Hands back the channel to pass the file info on to the receiver with.
+    pub fn acknowledge(&self, stream_id: Id, info: &FileInfoForStream) -> Option<StreamInfo> {
+        if let Some(s) = self.streams.lock().unwrap().get_mut(&stream_id) {
+            let available = match s.range {
+                Some(range) => range.resolve(info.size).map(|(start, end)| end - start + 1).unwrap_or(0),
+                None => info.size
+            };
+            s.expected = Some(available);
+            // The digest is for the whole file, so we can only check it if we're getting all of it:
+            if s.range.is_none() {
+                s.verifier = info.digest.as_ref()
+                    .and_then(|d| Sha256Digest::parse(d))
+                    .map(Verifier::new);
+            }
+        }
+        self.take_info(stream_id)
+    }
     pub fn take_data(&self, stream_id: Id) -> Option<StreamData> {
         match self.streams.lock().unwrap().get_mut(&stream_id) {
-            Some(s) => std::mem::replace(&mut s.data, None),
-            None => None
+            Some(s) if !s.segment_in_progress => {
+                let data = std::mem::replace(&mut s.data, None);
+                if data.is_some() { s.set_state(StreamState::Transferring) }
+                data
+            },
+            _ => None
+        }
+    }
+    /// Start receiving a segment of a stream. Unlike `take_data`, the stream keeps
+    /// hold of its channel, so that the receiver's download carries on if the
+    /// segment fails part of the way through. Only one segment can be received
+    /// at a time. Hands back a channel to send on and the bytes received so far.
+    pub fn start_segment(&self, stream_id: Id) -> Result<(StreamData, u64), SegmentError> {
+        let mut streams = self.streams.lock().unwrap();
+        let s = match streams.get_mut(&stream_id) {
+            Some(s) => s,
+            None => return Err(SegmentError::NotFound)
+        };
+        match s.state {
+            StreamState::Done => return Err(SegmentError::Complete),
+            StreamState::Failed => return Err(SegmentError::Failed),
+            _ => {}
+        }
+        if s.segment_in_progress {
+            return Err(SegmentError::Busy)
+        }
+        let data = match &s.data {
+            Some(data) => data.clone(),
+            None => return Err(SegmentError::Busy)
+        };
+        s.segment_in_progress = true;
+        s.set_state(StreamState::Transferring);
+        Ok((data, s.received))
+    }
+    /// Finish receiving a segment of a stream, whether or not it worked out. If we
+    /// have every byte that we expect, the stream is done. Returns true if so, or
+    /// an error if the bytes aren't what the sender said they'd be, in which case
+    /// the receiver's download is aborted.
+    pub fn end_segment(&self, stream_id: Id) -> Result<bool, Err> {
+        let mut streams = self.streams.lock().unwrap();
+        let s = match streams.get_mut(&stream_id) {
+            Some(s) => s,
+            None => return Ok(false)
+        };
+        s.segment_in_progress = false;
+        s.updated = Instant::now();
+        if s.data.as_ref().map(|d| d.is_closed()).unwrap_or(false) {
+            s.data = None;
+            s.set_state(StreamState::Failed);
+        } else if s.state == StreamState::Failed {
+            return Err(Err::new("Transfer failed"))
+        } else if s.expected.map(|expected| s.received >= expected).unwrap_or(false) {
+            if let Err(e) = s.verify() {
+                s.fail(e.clone());
+                return Err(e)
+            }
+            s.data = None;
+            s.set_state(StreamState::Done);
+        }
+        Ok(s.state == StreamState::Done)
+    }
+    /// Note that some more bytes of a stream are being passed on to the receiver. If
+    /// that takes us past the number of bytes that the sender said we'd get, the
+    /// stream fails and the receiver's download is aborted.
+    pub fn add_received(&self, stream_id: Id, bytes: &[u8]) -> Result<(), Err> {
+        if let Some(s) = self.streams.lock().unwrap().get_mut(&stream_id) {
+            s.received += bytes.len() as u64;
+            s.updated = Instant::now();
+            if let Some(expected) = s.expected {
+                if s.received > expected {
+                    let e = Err::new(format!("Upload is larger than the {} bytes expected", expected));
+                    s.fail(e.clone());
+                    return Err(e)
+                }
+            }
+            if let Some(verifier) = &mut s.verifier {
+                verifier.update(bytes);
+            }
         }
+        Ok(())
+    }
+    /// If every byte of a stream is in, check that they're what the sender said they'd be,
+    /// failing the stream if not. This needs doing before the last bytes are handed on to
+    /// the receiver, since once they have them all, they can't tell an error from a clean
+    /// finish.
+    pub fn verify_if_complete(&self, stream_id: Id) -> Result<(), Err> {
+        if let Some(s) = self.streams.lock().unwrap().get_mut(&stream_id) {
+            if s.expected.map(|expected| s.received >= expected).unwrap_or(false) {
+                if let Err(e) = s.verify() {
+                    s.fail(e.clone());
+                    return Err(e)
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Once every byte of a stream is in, check that they're what the sender said they'd be.
+    /// The receiver relies on getting the number of bytes that we told them to expect, so
+    /// an upload that ends short of that is a failure:
+    pub fn verify(&self, stream_id: Id) -> Result<(), Err> {
+        match self.streams.lock().unwrap().get_mut(&stream_id) {
+            Some(s) => s.verify(),
+            None => Ok(())
+        }
+    }
+    pub fn sender(&self, stream_id: Id) -> Option<Id> {
+        self.streams.lock().unwrap().get(&stream_id).map(|s| s.sender_id)
+    }
+    pub fn received(&self, stream_id: Id) -> Option<u64> {
+        self.streams.lock().unwrap().get(&stream_id).map(|s| s.received)
+    }
+    /// Mark a stream as done or failed. It's kept around for a little while
+    /// so that late requests for it get a sensible answer, and then reaped.
+    pub fn finish(&self, stream_id: Id, success: bool) {
+        if let Some(s) = self.streams.lock().unwrap().get_mut(&stream_id) {
+            s.info = None;
+            s.data = None;
+            s.set_state(if success { StreamState::Done } else { StreamState::Failed });
+        }
+    }
+    pub fn state(&self, stream_id: Id) -> Option<StreamState> {
+        self.streams.lock().unwrap().get(&stream_id).map(|s| s.state)
+    }
+    /// Requests for files from this sender that haven't started transferring yet, so that
+    /// they can be asked for again if the sender might not have seen them.
+    pub fn pending_requests(&self, sender_id: Id) -> Vec<MsgToSender> {
+        self.streams.lock().unwrap().iter()
+            .filter(|(_, s)| s.sender_id == sender_id && s.is_pending())
+            .filter_map(|(&stream_id, s)| {
+                s.file_id.map(|file_id| MsgToSender::PleaseUpload {
+                    file_id,
+                    stream_id,
+                    offset: s.range.map(|r| r.start).unwrap_or(0),
+                    length: s.range.and_then(|r| r.length())
+                })
+            })
+            .collect()
+    }
+    /// Fail any streams from this sender that haven't started transferring yet,
+    /// which lets the receivers waiting on them know straight away.
+    pub fn remove_for_sender(&self, sender_id: Id) {
+        for s in self.streams.lock().unwrap().values_mut() {
+            if s.sender_id == sender_id && s.is_pending() {
+                s.info = None;
+                s.data = None;
+                s.set_state(StreamState::Failed);
+            }
+        }
+    }
+    /// Remove streams that finished more than `finished_ttl` ago, and streams
+    /// that have been waiting on the sender for more than `pending_ttl`. Pending
+    /// streams are also removed as soon as the receiver has gone away. Streams
+    /// being uploaded in segments fail if no segment arrives within `segment_ttl`.
+    /// Transfers that stall part of the way through fail by themselves, since only
+    /// they can tell waiting on the sender apart from holding bytes back.
+    /// Hands back the number of streams removed.
+    pub fn reap(&self, pending_ttl: Duration, finished_ttl: Duration, segment_ttl: Duration) -> usize {
+        let mut streams = self.streams.lock().unwrap();
+        let before = streams.len();
+        for s in streams.values_mut() {
+            if s.state != StreamState::Transferring { continue }
+            let waiting_for_segment = s.data.is_some() && !s.segment_in_progress;
+            if waiting_for_segment && (s.updated.elapsed() >= segment_ttl || s.receiver_gone()) {
+                s.fail(Err::new("Timed out waiting for the next segment"));
+            }
+        }
+        streams.retain(|_, s| {
+            match s.state {
+                StreamState::AwaitingAck | StreamState::AwaitingUpload => {
+                    s.created.elapsed() < pending_ttl && !s.receiver_gone()
+                },
+                // Stalled segments have been failed above, so these are still going:
+                StreamState::Transferring => true,
+                StreamState::Done | StreamState::Failed => {
+                    s.updated.elapsed() < finished_ttl
+                }
+            }
+        });
+        before - streams.len()
     }
 }
 
-pub type StreamInfo = oneshot::Sender<FileInfoForStream>;
-pub type StreamData = Tx<Vec<u8>>;
+pub type StreamInfo = oneshot::Sender<Result<FileInfoForStream, NackReason>>;
+/// Chunks of file data for the receiver. An error aborts their download, rather
+/// than letting it end cleanly as if it was complete:
+pub type StreamData = Tx<Result<Vec<u8>, Err>>;
+
+/// Where a stream has got to:
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamState {
+    /// Waiting for the sender to acknowledge our request for the file:
+    AwaitingAck,
+    /// Acknowledged, and waiting for the sender to start uploading:
+    AwaitingUpload,
+    /// Bytes are flowing from the sender to the receiver:
+    Transferring,
+    /// Every byte made it to the receiver:
+    Done,
+    /// Something went wrong along the way:
+    Failed
+}
+
+/// Why a segment of a stream can't be received:
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentError {
+    /// There's no such stream:
+    NotFound,
+    /// Another segment or upload for this stream is in progress:
+    Busy,
+    /// We already have every byte of the stream:
+    Complete,
+    /// The stream has failed, so there's no point sending any more:
+    Failed
+}
 
 pub struct Stream {
+    sender_id: Id,
+    // The file asked for, if this stream is a download from the sender:
+    file_id: Option<Id>,
+    // The part of the file that was asked for, if not all of it:
+    range: Option<ByteRange>,
+    state: StreamState,
+    created: Instant,
+    updated: Instant,
+    // Bytes handed on to the receiver so far, and how many we expect in
+    // total (which we only know once the sender acknowledges the stream):
+    received: u64,
+    expected: Option<u64>,
+    // Checks the bytes against the digest the sender gave, if any:
+    verifier: Option<Verifier>,
+    segment_in_progress: bool,
     // These props are optional because they will be removed
     // separately from the stream and set to none.
     data: Option<StreamData>,
     info: Option<StreamInfo>
 }
 
+impl Stream {
+    fn set_state(&mut self, state: StreamState) {
+        self.state = state;
+        self.updated = Instant::now();
+    }
+    fn verify(&mut self) -> Result<(), Err> {
+        if let Some(expected) = self.expected {
+            if self.received < expected {
+                return Err(Err::new(format!("Upload ended {} bytes short of the {} expected", expected - self.received, expected)))
+            }
+        }
+        match self.verifier.take() {
+            Some(verifier) if !verifier.matches() => Err(Err::new("File does not match its digest")),
+            _ => Ok(())
+        }
+    }
+    // Fail the stream, telling the receiver if we still can:
+    fn fail(&mut self, e: Err) {
+        if let Some(mut data) = self.data.take() {
+            let _ = data.try_send(Err(e));
+        }
+        self.info = None;
+        self.set_state(StreamState::Failed);
+    }
+    fn is_pending(&self) -> bool {
+        self.state == StreamState::AwaitingAck || self.state == StreamState::AwaitingUpload
+    }
+    // Has the receiver stopped waiting for the info or the data?
+    fn receiver_gone(&self) -> bool {
+        let info_gone = self.info.as_ref().map(|i| i.is_canceled()).unwrap_or(false);
+        let data_gone = self.data.as_ref().map(|d| d.is_closed()).unwrap_or(false);
+        info_gone || data_gone
+    }
+}
+
+/// Files that visitors are offering to a sender's drop box. This is a download
+/// with the roles reversed: the visitor uploads, and once the sender accepts the
+/// file, they download it. Offers are keyed by the ID of the stream relaying them.
+pub struct Incoming {
+    offers: Mutex<HashMap<Id, Offer>>
+}
+
+impl Incoming {
+    pub fn new() -> Incoming {
+        Incoming {
+            offers: Mutex::new(HashMap::new())
+        }
+    }
+    pub fn add(&self, stream_id: Id, offer: Offer) {
+        self.offers.lock().unwrap().insert(stream_id, offer);
+    }
+    /// The sender wants the file. They can now download it.
+    pub fn accept(&self, sender_id: Id, stream_id: Id) -> bool {
+        match self.offers.lock().unwrap().get_mut(&stream_id) {
+            Some(offer) if offer.sender_id == sender_id => { offer.decide(Ok(())); true },
+            _ => false
+        }
+    }
+    /// The sender doesn't want the file, so we forget about it.
+    pub fn reject(&self, sender_id: Id, stream_id: Id, reason: Option<String>) -> bool {
+        let mut offers = self.offers.lock().unwrap();
+        if offers.get(&stream_id).map(|o| o.sender_id) != Some(sender_id) {
+            return false
+        }
+        match offers.remove(&stream_id) {
+            Some(mut offer) => { offer.decide(Err(reason)); true },
+            None => false
+        }
+    }
+    /// The sender has come to download the file. If they didn't accept it first,
+    /// this counts as accepting it.
+    pub fn take(&self, stream_id: Id) -> Option<(FileInfoForStream, FileData)> {
+        let mut offer = self.offers.lock().unwrap().remove(&stream_id)?;
+        offer.decide(Ok(()));
+        if let Some(picked_up) = offer.picked_up.take() {
+            let _ = picked_up.send(());
+        }
+        Some((offer.info, offer.data))
+    }
+    pub fn remove(&self, stream_id: Id) -> bool {
+        self.offers.lock().unwrap().remove(&stream_id).is_some()
+    }
+    /// Forget about offers to this sender, which lets the visitors know.
+    pub fn remove_for_sender(&self, sender_id: Id) {
+        self.offers.lock().unwrap().retain(|_, offer| offer.sender_id != sender_id);
+    }
+    /// Forget about offers from visitors that have gone away, handing back how many.
+    pub fn reap(&self) -> usize {
+        let mut offers = self.offers.lock().unwrap();
+        let before = offers.len();
+        offers.retain(|_, offer| !offer.visitor_gone());
+        before - offers.len()
+    }
+}
+
+/// Whether the sender accepted a file, or rejected it with an optional reason:
+pub type Decision = Result<(), Option<String>>;
+
+pub struct Offer {
+    pub sender_id: Id,
+    pub info: FileInfoForStream,
+    pub decision: Option<oneshot::Sender<Decision>>,
+    pub picked_up: Option<oneshot::Sender<()>>,
+    pub data: FileData
+}
+
+impl Offer {
+    fn decide(&mut self, decision: Decision) {
+        if let Some(tx) = self.decision.take() {
+            let _ = tx.send(decision);
+        }
+    }
+    fn visitor_gone(&self) -> bool {
+        self.decision.as_ref().map(|d| d.is_canceled()).unwrap_or(false)
+            || self.picked_up.as_ref().map(|p| p.is_canceled()).unwrap_or(false)
+    }
+}
+
 /// State holds everything the application needs to share
 pub struct State {
     pub senders: Senders,
     pub receivers: Receivers,
-    pub streams: Streams
+    pub streams: Streams,
+    pub incoming: Incoming,
+    pub tokens: Tokens,
+    pub attempts: Attempts,
+    pub multicasts: Multicasts,
+    /// HTTP requests from each address:
+    pub http_limits: Limiter<Option<IpAddr>>,
+    /// Downloads by each receiver, which are counted by address over HTTP, since
+    /// that's all we know about who's downloading:
+    pub receiver_download_limits: Limiter<Id>,
+    pub addr_download_limits: Limiter<Option<IpAddr>>,
+    pub throttles: Throttles,
+    pub options: Options
 }
 
 impl State {
-    pub fn new() -> State {
+    pub fn new(options: Options) -> State {
         State {
             senders: Senders::new(),
             receivers: Receivers::new(),
-            streams: Streams::new()
+            streams: Streams::new(),
+            incoming: Incoming::new(),
+            tokens: Tokens::new(Duration::from_secs(options.token_ttl)),
+            attempts: Attempts::new(
+                options.password_attempts,
+                options.password_share_attempts,
+                Duration::from_secs(options.password_window)
+            ),
+            multicasts: Multicasts::new(
+                options.multicast_buffer,
+                Duration::from_secs(options.multicast_slow_timeout)
+            ),
+            http_limits: Limiter::new(options.http_rate, options.http_burst),
+            receiver_download_limits: Limiter::new(options.download_rate, options.download_burst),
+            addr_download_limits: Limiter::new(options.download_rate, options.download_burst),
+            throttles: Throttles::new(options.bandwidth, options.sender_bandwidth, options.stream_bandwidth),
+            options: options
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::{Future, Stream};
+
+    fn add_receiver(receivers: &Receivers, sender_id: Id, waiting: Option<Waiting>, password: Option<PasswordHash>) -> (Id, mpsc::UnboundedReceiver<MsgToReceiver>) {
+        let (tx, rx) = mpsc::unbounded();
+        let (receiver_id, _) = receivers.add(sender_id, tx, None, None, waiting, password).unwrap();
+        (receiver_id, rx)
+    }
+
+    // Receivers that are turned away are removed, which closes their channel:
+    fn turned_away(rx: mpsc::UnboundedReceiver<MsgToReceiver>) -> Vec<MsgToReceiver> {
+        rx.collect().wait().unwrap()
+    }
+
+    fn ids(online: Vec<(Id, Vec<Id>)>) -> HashSet<Id> {
+        online.into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn new_password_turns_receivers_away() {
+        let senders = Senders::new();
+        let receivers = Receivers::new();
+        let old = PasswordHash::new("old");
+        let new = PasswordHash::new("new");
+
+        let (sender_tx, _sender_rx) = mpsc::unbounded();
+        let (sender_id, token) = senders.add(sender_tx, None, None, Some(old.clone())).unwrap();
+        let (knew_old, old_rx) = add_receiver(&receivers, sender_id, None, Some(old.clone()));
+        let (knew_none, none_rx) = add_receiver(&receivers, sender_id, None, None);
+
+        // The sender reclaims its ID with a different password:
+        let (sender_tx, _sender_rx) = mpsc::unbounded();
+        senders.add(sender_tx, Some(sender_id), Some(&token), Some(new.clone())).unwrap();
+        let (knew_new, _new_rx) = add_receiver(&receivers, sender_id, None, Some(new.clone()));
+        let waiting = Waiting { addr: None, password: Some("new".to_owned()) };
+        let (checked_new, _checked_rx) = add_receiver(&receivers, sender_id, Some(waiting), None);
+
+        let password = senders.get(sender_id).and_then(|s| s.password);
+        assert!(password == Some(new.clone()));
+        let checked = vec![(checked_new, Ok(Some(new.clone())))];
+        let online = receivers.write().sender_online(sender_id, password.as_ref(), &checked);
+
+        // Only receivers that proved they know the new password are let in:
+        assert_eq!(ids(online), vec![knew_new, checked_new].into_iter().collect());
+        assert!(receivers.get(knew_old).is_none());
+        assert!(receivers.get(knew_none).is_none());
+        assert_eq!(turned_away(old_rx), vec![MsgToReceiver::HandshakeRejected { reason: RejectReason::PasswordIncorrect }]);
+        assert_eq!(turned_away(none_rx), vec![MsgToReceiver::HandshakeRejected { reason: RejectReason::PasswordRequired }]);
+        assert!(receivers.get(checked_new).unwrap().password == Some(new));
+    }
+
+    #[test]
+    fn reclaiming_keeps_limits_and_counts() {
+        let senders = Senders::new();
+        let file_id = IdGen::new().make_id();
+        let addr = Some(IpAddr::from([10, 0, 0, 1]));
+
+        let (sender_tx, _sender_rx) = mpsc::unbounded();
+        let (sender_id, token) = senders.add(sender_tx, None, None, None).unwrap();
+        senders.set_limits(sender_id, None, Limits::new(None, Some(1)));
+        senders.update_files(sender_id, |files| files.push(File {
+            id: file_id.to_string(),
+            name: "file.txt".to_owned(),
+            size: 5,
+            modified: None,
+            hash: None,
+            encrypted: false
+        }));
+        assert!(senders.start_download(sender_id, file_id, addr, false).is_ok());
+
+        // Reclaiming the ID before the old connection has gone doesn't reset anything:
+        let (sender_tx, _sender_rx) = mpsc::unbounded();
+        senders.add(sender_tx, Some(sender_id), Some(&token), None).unwrap();
+        assert_eq!(senders.get(sender_id).unwrap().files.len(), 1);
+        assert_eq!(senders.check_download(sender_id, file_id, addr, false), Err(UnavailableReason::DownloadLimitReached));
+        // The address that already has the file can still resume it:
+        assert!(senders.is_resume(sender_id, file_id, addr, true));
+    }
+
+    #[test]
+    fn no_password_lets_receivers_in() {
+        let receivers = Receivers::new();
+        let sender_id = IdGen::new().make_id();
+        let old = PasswordHash::new("old");
+
+        let (knew_old, _old_rx) = add_receiver(&receivers, sender_id, None, Some(old));
+        let (knew_none, _none_rx) = add_receiver(&receivers, sender_id, None, None);
+
+        // The sender came back without a password, so nobody needs one:
+        let online = receivers.write().sender_online(sender_id, None, &[]);
+        assert_eq!(ids(online), vec![knew_old, knew_none].into_iter().collect());
+        assert!(receivers.get(knew_old).unwrap().password.is_none());
+    }
+}
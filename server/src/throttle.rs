@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::id::Id;
+
+/// Limits on how many bytes per second are relayed: across the whole server,
+/// for each sender and for each stream. A limit of 0 means that there isn't one.
+/// Rather than dropping anything, uploads are paced by holding each chunk back
+/// until it fits within every limit.
+pub struct Throttles {
+    global: Mutex<Pacer>,
+    senders: Mutex<HashMap<Id, Pacer>>,
+    streams: Mutex<HashMap<Id, Pacer>>,
+    global_rate: u64,
+    sender_rate: u64,
+    stream_rate: u64
+}
+
+impl Throttles {
+    pub fn new(global_rate: u64, sender_rate: u64, stream_rate: u64) -> Throttles {
+        Throttles {
+            global: Mutex::new(Pacer::new()),
+            senders: Mutex::new(HashMap::new()),
+            streams: Mutex::new(HashMap::new()),
+            global_rate,
+            sender_rate,
+            stream_rate
+        }
+    }
+    /// How long to wait before asking again to pass on a chunk of `bytes` from this sender
+    /// and stream, or zero if it can go now. The chunk can only go once every limit has room
+    /// for it, and is only booked into them once it goes. Booking a future slot instead would
+    /// mean that a chunk held back by the limit on its own stream takes up room on the wider
+    /// limits while it waits, holding up other streams for no reason.
+    pub fn delay(&self, sender_id: Id, stream_id: Id, bytes: usize) -> Duration {
+        let mut global = self.global.lock().unwrap();
+        let mut senders = self.senders.lock().unwrap();
+        let mut streams = self.streams.lock().unwrap();
+
+        let mut pacers = Vec::with_capacity(3);
+        if self.global_rate > 0 {
+            pacers.push((&mut *global, self.global_rate));
+        }
+        if self.sender_rate > 0 {
+            pacers.push((senders.entry(sender_id).or_insert_with(Pacer::new), self.sender_rate));
+        }
+        if self.stream_rate > 0 {
+            pacers.push((streams.entry(stream_id).or_insert_with(Pacer::new), self.stream_rate));
+        }
+
+        let now = Instant::now();
+        let ready = pacers.iter().fold(now, |ready, (p, _)| std::cmp::max(ready, p.next_free));
+        if ready > now {
+            return ready - now
+        }
+        for (pacer, rate) in pacers {
+            pacer.book(now, rate, bytes);
+        }
+        Duration::from_secs(0)
+    }
+    /// Forget about senders and streams that haven't sent anything for long enough
+    /// to have caught up with their limits, since a new pacer would be just the same:
+    pub fn reap(&self) {
+        let now = Instant::now();
+        self.senders.lock().unwrap().retain(|_, p| p.next_free > now);
+        self.streams.lock().unwrap().retain(|_, p| p.next_free > now);
+    }
+}
+
+/// Keeps track of when the next chunk can go, given the chunks that have gone so far.
+struct Pacer {
+    next_free: Instant
+}
+
+impl Pacer {
+    fn new() -> Pacer {
+        Pacer { next_free: Instant::now() }
+    }
+    // Book the slot for some bytes that starts at `start`, which is no earlier than `next_free`:
+    fn book(&mut self, start: Instant, rate: u64, bytes: usize) {
+        let nanos = bytes as u128 * 1_000_000_000 / rate as u128;
+        self.next_free = start + Duration::from_nanos(nanos as u64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::id::IdGen;
+
+    fn roughly(d: Duration, millis: u64) -> bool {
+        let d = d.as_millis() as i64;
+        (d - millis as i64).abs() < 50
+    }
+
+    #[test]
+    fn waiting_streams_dont_hold_up_others() {
+        let mut ids = IdGen::new();
+        let (sender, a, b) = (ids.make_id(), ids.make_id(), ids.make_id());
+        let throttles = Throttles::new(10_000, 0, 100);
+
+        assert!(roughly(throttles.delay(sender, a, 100), 0));
+        // Stream a has to wait a second for its own limit...
+        assert!(roughly(throttles.delay(sender, a, 100), 1000));
+        // ...but it hasn't taken up any of the global limit while it waits,
+        // which has plenty of room, so b goes straight away:
+        assert!(roughly(throttles.delay(sender, b, 100), 0));
+        assert!(roughly(throttles.delay(sender, b, 100), 0));
+        // a still has to wait for its own limit when it asks again:
+        assert!(roughly(throttles.delay(sender, a, 100), 1000));
+    }
+
+    #[test]
+    fn every_limit_is_booked_once_a_chunk_goes() {
+        let mut ids = IdGen::new();
+        let (sender, a, b) = (ids.make_id(), ids.make_id(), ids.make_id());
+        let throttles = Throttles::new(1000, 500, 0);
+
+        assert!(roughly(throttles.delay(sender, a, 100), 0));
+        // The global limit is free again after 100ms, but the sender's isn't until 200ms:
+        assert!(roughly(throttles.delay(sender, b, 100), 200));
+    }
+
+    #[test]
+    fn no_limits_means_no_waiting() {
+        let mut ids = IdGen::new();
+        let (sender, stream) = (ids.make_id(), ids.make_id());
+        let throttles = Throttles::new(0, 0, 0);
+        for _ in 0..10 {
+            assert_eq!(throttles.delay(sender, stream, 1_000_000), Duration::from_secs(0));
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType, SanType};
+use sha2::{Digest, Sha256};
+use crate::Err;
+
+/// A self signed certificate for serving HTTPS on the local network without
+/// having to get hold of a real one. It's kept in a directory so that browsers
+/// that have been told to trust it keep doing so when the server restarts.
+pub struct SelfSigned {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// The SHA-256 fingerprint of the certificate, for people to check their
+    /// browser is seeing the same one:
+    pub fingerprint: String
+}
+
+impl SelfSigned {
+    /// Load the certificate from `dir`, or make one if there isn't one there yet.
+    pub fn load_or_generate(dir: &Path, address: SocketAddr) -> Result<SelfSigned, Err> {
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+
+        if !cert.exists() || !key.exists() {
+            let (cert_pem, key_pem) = generate(address)?;
+            fs::create_dir_all(dir)
+                .map_err(|e| Err::new(format!("Can't create {}: {}", dir.display(), e)))?;
+            write_private(&key, &key_pem)?;
+            fs::write(&cert, cert_pem)
+                .map_err(|e| Err::new(format!("Can't write {}: {}", cert.display(), e)))?;
+        }
+
+        let cert_pem = fs::read_to_string(&cert)
+            .map_err(|e| Err::new(format!("Can't read {}: {}", cert.display(), e)))?;
+        let fingerprint = fingerprint(&cert_pem)?;
+        Ok(SelfSigned { cert, key, fingerprint })
+    }
+}
+
+// Make a certificate that's good for every name and address that we might be reached at:
+fn generate(address: SocketAddr) -> Result<(String, String), Err> {
+    let mut names = vec![SanType::DnsName("localhost".to_owned())];
+    if let Ok(hostname) = hostname::get() {
+        if let Some(hostname) = hostname.to_str() {
+            names.push(SanType::DnsName(hostname.to_owned()));
+            names.push(SanType::DnsName(format!("{}.local", hostname)));
+        }
+    }
+    for ip in addresses(address.ip()) {
+        names.push(SanType::IpAddress(ip));
+    }
+
+    let mut params = CertificateParams::default();
+    params.subject_alt_names = names;
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, "File Streamer");
+
+    let cert = Certificate::from_params(params)
+        .map_err(|e| Err::new(format!("Can't generate certificate: {}", e)))?;
+    let cert_pem = cert.serialize_pem()
+        .map_err(|e| Err::new(format!("Can't encode certificate: {}", e)))?;
+    Ok((cert_pem, cert.serialize_private_key_pem()))
+}
+
+// If we're listening on every interface, the certificate needs every address we have:
+fn addresses(listening: IpAddr) -> Vec<IpAddr> {
+    let mut ips = vec![IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)];
+    if listening.is_unspecified() {
+        if let Ok(interfaces) = if_addrs::get_if_addrs() {
+            ips.extend(interfaces.iter().map(|i| i.ip()));
+        }
+    } else {
+        ips.push(listening);
+    }
+    ips.sort();
+    ips.dedup();
+    ips
+}
+
+// Nobody else should be able to read the private key:
+fn write_private(path: &Path, contents: &str) -> Result<(), Err> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let write = options.open(path).and_then(|mut file| {
+        use std::io::Write;
+        file.write_all(contents.as_bytes())
+    });
+    write.map_err(|e| Err::new(format!("Can't write {}: {}", path.display(), e)))
+}
+
+// The fingerprint is the SHA-256 hash of the DER encoded certificate, in the
+// colon separated hex that browsers show:
+fn fingerprint(cert_pem: &str) -> Result<String, Err> {
+    let encoded: String = cert_pem.lines()
+        .skip_while(|l| !l.starts_with("-----BEGIN CERTIFICATE-----"))
+        .skip(1)
+        .take_while(|l| !l.starts_with("-----END CERTIFICATE-----"))
+        .collect();
+    let der = base64::decode(&encoded)
+        .map_err(|e| Err::new(format!("Certificate isn't valid PEM: {}", e)))?;
+    let hex: Vec<String> = Sha256::digest(&der).iter().map(|b| format!("{:02X}", b)).collect();
+    Ok(hex.join(":"))
+}